@@ -0,0 +1,423 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    time::{Duration, SystemTime},
+};
+
+use hickory_resolver::TokioResolver;
+use humantime::{format_duration, format_rfc3339_seconds};
+use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender, unbounded_channel};
+use tracing::{Instrument, info, info_span, warn};
+
+use crate::{
+    config::{Target, Webhook},
+    fault::Fault,
+    http::{self, Request},
+    state::{State, Transition},
+};
+
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Placeholders available in webhook body templates.
+pub const TEMPLATE_FIELDS: [&str; 9] = [
+    "target",
+    "label",
+    "state",
+    "previous_state",
+    "duration",
+    "duration_seconds",
+    "lost",
+    "fault",
+    "timestamp",
+];
+
+pub const DEFAULT_TEMPLATE: &str = r#"{"target":"{{target}}","label":"{{label}}","state":"{{state}}","previous_state":"{{previous_state}}","duration_seconds":{{duration_seconds}},"lost":{{lost}},"timestamp":"{{timestamp}}"}"#;
+
+/// A target state change delivered to the alert sinks.
+#[derive(Clone, Debug)]
+pub struct Event {
+    pub target: String,
+    pub label: Option<String>,
+    pub state: State,
+    pub previous: State,
+    /// Time since the target was last up.
+    pub duration: Duration,
+    /// Probes lost since the target was last up.
+    pub lost: u64,
+    /// Where the outage is, set when the target goes down.
+    pub fault: Option<Fault>,
+    pub at: SystemTime,
+}
+
+impl Event {
+    pub fn new(target: &Target, transition: &Transition, fault: Option<Fault>) -> Self {
+        Self {
+            target: target.address.to_string(),
+            label: target.label.clone(),
+            state: transition.to,
+            previous: transition.from,
+            duration: transition.duration,
+            lost: transition.lost,
+            fault,
+            at: SystemTime::now(),
+        }
+    }
+
+    /// Returns the value of a [`TEMPLATE_FIELDS`] field.
+    pub fn field(&self, name: &str) -> String {
+        match name {
+            "target" => self.target.clone(),
+            "label" => self.label.clone().unwrap_or_default(),
+            "state" => self.state.to_string(),
+            "previous_state" => self.previous.to_string(),
+            "duration" => format_duration(Duration::from_secs(self.duration.as_secs())).to_string(),
+            "duration_seconds" => self.duration.as_secs().to_string(),
+            "lost" => self.lost.to_string(),
+            "fault" => self
+                .fault
+                .map(|fault| fault.to_string())
+                .unwrap_or_default(),
+            "timestamp" => format_rfc3339_seconds(self.at).to_string(),
+            _ => String::new(),
+        }
+    }
+}
+
+/// Decides which events a sink acts on.
+///
+/// An event passes if its state is subscribed and differs from the last state
+/// the sink acted on for that target, so recovering from a short degradation
+/// nobody was told about doesn't produce a lone "up" alert.
+#[derive(Default)]
+pub struct EventFilter {
+    notified: HashMap<(String, Option<String>), State>,
+}
+
+impl EventFilter {
+    pub fn accept(&mut self, states: &[State], event: &Event) -> bool {
+        let key = (event.target.clone(), event.label.clone());
+        let last = self.notified.get(&key).copied().unwrap_or(State::Up);
+
+        if !states.contains(&event.state) {
+            if event.state == State::Up {
+                self.notified.remove(&key);
+            }
+
+            return false;
+        }
+
+        if event.state == last {
+            return false;
+        }
+
+        self.notified.insert(key, event.state);
+
+        true
+    }
+}
+
+/// Fans target state changes out to the configured alert sinks.
+#[derive(Clone, Default)]
+pub struct Alerts {
+    sinks: Vec<UnboundedSender<Event>>,
+}
+
+impl Alerts {
+    /// Spawns a delivery task for every webhook.
+    pub fn new(webhooks: Vec<Webhook>, resolver: TokioResolver) -> Self {
+        let mut sinks = Vec::new();
+
+        for webhook in webhooks {
+            let (tx, rx) = unbounded_channel();
+            let span = info_span!("webhook", url = %webhook.url);
+
+            tokio::spawn(deliver_webhook(webhook, resolver.clone(), rx).instrument(span));
+            sinks.push(tx);
+        }
+
+        Self { sinks }
+    }
+
+    pub fn notify(&self, event: Event) {
+        for sink in &self.sinks {
+            let _ = sink.send(event.clone());
+        }
+    }
+}
+
+/// Delivers webhook alerts in order. Failed deliveries are retried with
+/// exponential backoff while new alerts queue up behind them, so alerts fired
+/// while the uplink is down are sent once it comes back.
+async fn deliver_webhook(
+    webhook: Webhook,
+    resolver: TokioResolver,
+    mut events: UnboundedReceiver<Event>,
+) {
+    let mut filter = EventFilter::default();
+    let mut queue = VecDeque::new();
+    let mut backoff = INITIAL_BACKOFF;
+    let mut attempts = 0;
+
+    loop {
+        if queue.is_empty() {
+            let Some(event) = events.recv().await else {
+                return;
+            };
+
+            if filter.accept(&webhook.events, &event) {
+                queue.push_back(event);
+            }
+        }
+
+        while let Ok(event) = events.try_recv() {
+            if filter.accept(&webhook.events, &event) {
+                queue.push_back(event);
+            }
+        }
+
+        while queue.len() > webhook.queue_size {
+            if let Some(event) = queue.pop_front() {
+                warn!(
+                    "Alert queue full, dropping {} alert for {}",
+                    event.state, event.target
+                );
+            }
+        }
+
+        let Some(event) = queue.front() else {
+            continue;
+        };
+
+        attempts += 1;
+
+        match send_webhook(&webhook, &resolver, event).await {
+            Ok(()) => {
+                if attempts > 1 {
+                    info!(
+                        "Delivered {} alert for {} after {} attempts",
+                        event.state, event.target, attempts
+                    );
+                }
+
+                queue.pop_front();
+                backoff = INITIAL_BACKOFF;
+                attempts = 0;
+            }
+            Err(err) => {
+                warn!(
+                    "Failed to deliver {} alert for {}, retrying in {}, error: {}",
+                    event.state,
+                    event.target,
+                    format_duration(backoff),
+                    err
+                );
+
+                tokio::time::sleep(backoff).await;
+                backoff = (backoff * 2).min(webhook.max_backoff);
+            }
+        }
+    }
+}
+
+async fn send_webhook(
+    webhook: &Webhook,
+    resolver: &TokioResolver,
+    event: &Event,
+) -> Result<(), String> {
+    let body = render(&webhook.body, event);
+
+    let response = http::send(
+        resolver,
+        &Request {
+            method: &webhook.method,
+            url: &webhook.url,
+            headers: &webhook.headers,
+            body: body.as_bytes(),
+            timeout: webhook.timeout,
+        },
+    )
+    .await?;
+
+    if !(200..300).contains(&response.status) {
+        let body = String::from_utf8_lossy(&response.body);
+
+        return Err(format!(
+            "unexpected status {}: {}",
+            response.status,
+            body.trim().chars().take(200).collect::<String>()
+        ));
+    }
+
+    Ok(())
+}
+
+/// Returns the placeholder names used in a template.
+pub fn placeholders(template: &str) -> impl Iterator<Item = &str> {
+    template
+        .split("{{")
+        .skip(1)
+        .filter_map(|part| part.split_once("}}"))
+        .map(|(name, _)| name.trim())
+}
+
+/// Substitutes `{{field}}` placeholders with JSON string escaped event fields.
+fn render(template: &str, event: &Event) -> String {
+    let mut out = String::with_capacity(template.len());
+    let mut rest = template;
+
+    while let Some(start) = rest.find("{{") {
+        let Some(end) = rest[start..].find("}}") else {
+            break;
+        };
+
+        out.push_str(&rest[..start]);
+        out.push_str(&escape_json(
+            &event.field(rest[start + 2..start + end].trim()),
+        ));
+        rest = &rest[start + end + 2..];
+    }
+
+    out.push_str(rest);
+    out
+}
+
+fn escape_json(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+
+    for ch in value.chars() {
+        match ch {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            ch if ch.is_control() => out.push_str(&format!("\\u{:04x}", ch as u32)),
+            ch => out.push(ch),
+        }
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(state: State, previous: State) -> Event {
+        Event {
+            target: "192.0.2.1".to_string(),
+            label: Some("gateway".to_string()),
+            state,
+            previous,
+            duration: Duration::from_millis(90_500),
+            lost: 7,
+            fault: None,
+            at: SystemTime::UNIX_EPOCH,
+        }
+    }
+
+    #[test]
+    fn filter_passes_subscribed_state_changes() {
+        let mut filter = EventFilter::default();
+        let states = [State::Down, State::Up];
+
+        assert!(filter.accept(&states, &event(State::Down, State::Degraded)));
+        assert!(filter.accept(&states, &event(State::Up, State::Down)));
+        assert!(filter.accept(&states, &event(State::Down, State::Degraded)));
+    }
+
+    #[test]
+    fn filter_suppresses_up_after_unreported_degradation() {
+        let mut filter = EventFilter::default();
+        let states = [State::Down, State::Up];
+
+        assert!(!filter.accept(&states, &event(State::Degraded, State::Up)));
+        assert!(!filter.accept(&states, &event(State::Up, State::Degraded)));
+    }
+
+    #[test]
+    fn filter_suppresses_repeated_state() {
+        let mut filter = EventFilter::default();
+        let states = [State::Down, State::Up];
+
+        assert!(filter.accept(&states, &event(State::Down, State::Degraded)));
+        // down -> degraded -> down is still the same outage for the sink
+        assert!(!filter.accept(&states, &event(State::Degraded, State::Down)));
+        assert!(!filter.accept(&states, &event(State::Down, State::Degraded)));
+    }
+
+    #[test]
+    fn filter_resets_on_unsubscribed_up() {
+        let mut filter = EventFilter::default();
+        let states = [State::Down];
+
+        assert!(filter.accept(&states, &event(State::Down, State::Degraded)));
+        assert!(!filter.accept(&states, &event(State::Up, State::Down)));
+        assert!(filter.accept(&states, &event(State::Down, State::Degraded)));
+    }
+
+    #[test]
+    fn filter_tracks_targets_separately() {
+        let mut filter = EventFilter::default();
+        let states = [State::Down];
+        let mut other = event(State::Down, State::Degraded);
+        other.label = None;
+
+        assert!(filter.accept(&states, &event(State::Down, State::Degraded)));
+        assert!(filter.accept(&states, &other));
+    }
+
+    #[test]
+    fn placeholders_are_trimmed() {
+        let found = placeholders("{{target}} {{ state }} {{ unclosed").collect::<Vec<_>>();
+
+        assert_eq!(found, ["target", "state"]);
+    }
+
+    #[test]
+    fn render_substitutes_fields() {
+        let body = render(
+            "{{target}} {{ label }} {{state}} {{previous_state}} {{duration}} \
+             {{duration_seconds}} {{lost}} {{timestamp}}",
+            &event(State::Up, State::Down),
+        );
+
+        assert_eq!(
+            body,
+            "192.0.2.1 gateway up down 1m 30s 90 7 1970-01-01T00:00:00Z"
+        );
+    }
+
+    #[test]
+    fn render_default_template_is_json() {
+        let mut event = event(State::Down, State::Degraded);
+        event.label = None;
+
+        assert_eq!(
+            render(DEFAULT_TEMPLATE, &event),
+            r#"{"target":"192.0.2.1","label":"","state":"down","previous_state":"degraded","duration_seconds":90,"lost":7,"timestamp":"1970-01-01T00:00:00Z"}"#
+        );
+    }
+
+    #[test]
+    fn render_keeps_unclosed_placeholder() {
+        let body = render("{{state}} {{state", &event(State::Down, State::Up));
+
+        assert_eq!(body, "down {{state");
+    }
+
+    #[test]
+    fn render_escapes_fields() {
+        let mut event = event(State::Down, State::Up);
+        event.label = Some("a \"quoted\"\\\nlabel\u{1}".to_string());
+
+        assert_eq!(
+            render("{{label}}", &event),
+            r#"a \"quoted\"\\\nlabel\u0001"#
+        );
+    }
+
+    #[test]
+    fn escape_json_passes_unicode() {
+        assert_eq!(escape_json("ünïcode\t✓"), "ünïcode\\t✓");
+    }
+}
@@ -0,0 +1,42 @@
+use std::{io, net::SocketAddr};
+
+use socket2::Socket;
+
+use crate::config::Binding;
+
+/// Binds the socket to the interface, firewall mark and source address of
+/// the binding, before it connects or sends anything.
+pub fn apply(socket: &Socket, binding: &Binding) -> io::Result<()> {
+    set_device(socket, binding)?;
+
+    if let Some(source) = binding.source {
+        socket.bind(&SocketAddr::new(source, 0).into())?;
+    }
+
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+fn set_device(socket: &Socket, binding: &Binding) -> io::Result<()> {
+    if let Some(interface) = &binding.interface {
+        socket.bind_device(Some(interface.as_bytes()))?;
+    }
+
+    if let Some(mark) = binding.fwmark {
+        socket.set_mark(mark)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_device(_socket: &Socket, binding: &Binding) -> io::Result<()> {
+    if binding.interface.is_some() || binding.fwmark.is_some() {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "binding to an interface and firewall marks are only supported on Linux",
+        ));
+    }
+
+    Ok(())
+}
@@ -0,0 +1,1510 @@
+use std::{
+    collections::BTreeMap,
+    fmt, fs,
+    net::{IpAddr, SocketAddr},
+    num::{NonZeroU8, NonZeroU32, NonZeroUsize},
+    path::PathBuf,
+    str::FromStr,
+    time::Duration,
+};
+
+use clap::ValueEnum;
+use file_rotate::TimeFrequency;
+use hickory_resolver::{Name, proto::rr::RecordType};
+use humantime::{format_duration, parse_duration};
+use serde::{Deserialize, Deserializer};
+use url::Url;
+
+use crate::{
+    Args,
+    alert::{DEFAULT_TEMPLATE, TEMPLATE_FIELDS, placeholders},
+    http::{validate_header, validate_method, validate_url},
+    state::State,
+};
+
+const DEFAULT_INTERVAL: Duration = Duration::from_secs(5);
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(4);
+const DEFAULT_RESOLVE_INTERVAL: Duration = Duration::from_secs(300);
+const DEFAULT_DOWN_AFTER: NonZeroU32 = NonZeroU32::new(3).unwrap();
+const DEFAULT_UP_AFTER: NonZeroU32 = NonZeroU32::new(2).unwrap();
+const DEFAULT_BURST: NonZeroU32 = NonZeroU32::new(1).unwrap();
+const DEFAULT_BURST_GAP: Duration = Duration::from_millis(100);
+const DEFAULT_PMTU_INTERVAL: Duration = Duration::from_secs(10 * 60);
+const DEFAULT_LOG_FILE: &str = "pinger.log";
+const DEFAULT_LOG_KEEP: usize = 3;
+const DEFAULT_WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+const DEFAULT_WEBHOOK_MAX_BACKOFF: Duration = Duration::from_secs(300);
+const DEFAULT_WEBHOOK_QUEUE_SIZE: usize = 100;
+const DEFAULT_HOOK_TIMEOUT: Duration = Duration::from_secs(30);
+const DEFAULT_HOOK_MAX_CONCURRENT: usize = 4;
+const DEFAULT_DNS_PORT: u16 = 53;
+const DEFAULT_ICMP_SIZE: usize = 56;
+/// Largest ICMP echo payload that fits into an IPv4 packet.
+const MAX_ICMP_SIZE: usize = 65507;
+const MAX_PATTERN_SIZE: usize = 16;
+/// Longest interface name, `IFNAMSIZ` without the terminating NUL.
+const MAX_INTERFACE_LEN: usize = 15;
+const DEFAULT_CERT_EXPIRY: Duration = Duration::from_secs(14 * 24 * 60 * 60);
+/// Largest payload of a UDP datagram over IPv4.
+const MAX_UDP_PAYLOAD: usize = 65507;
+const DEFAULT_STATS_INTERVAL: Duration = Duration::from_secs(60);
+const DEFAULT_STATS_WINDOWS: [Duration; 3] = [
+    Duration::from_secs(60),
+    Duration::from_secs(300),
+    Duration::from_secs(3600),
+];
+
+#[derive(ValueEnum, Deserialize, Clone, Copy, Default, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum LogRotation {
+    /// Rotate every hour.
+    Hourly,
+    /// Rotate one time a day.
+    #[default]
+    Daily,
+    /// Rotate ones a week.
+    Weekly,
+    /// Rotate every month.
+    Monthly,
+    /// Rotate yearly.
+    Yearly,
+}
+
+#[derive(ValueEnum, Deserialize, Clone, Copy, Default, Debug, PartialEq, Eq, Hash)]
+#[serde(rename_all = "kebab-case")]
+pub enum Socket {
+    /// Raw socket, may require root privileges.
+    #[default]
+    Raw,
+    /// Datagram socket.
+    Datagram,
+}
+
+#[derive(ValueEnum, Deserialize, Clone, Copy, Default, Debug, PartialEq, Eq, Hash)]
+#[serde(rename_all = "kebab-case")]
+pub enum ReplyLevel {
+    /// Don't log successful replies.
+    Off,
+    /// Log replies at trace level.
+    Trace,
+    /// Log replies at debug level.
+    #[default]
+    Debug,
+    /// Log replies at info level.
+    Info,
+}
+
+/// Address family used for hostname targets.
+#[derive(Deserialize, Clone, Copy, Default, Debug, PartialEq, Eq, Hash)]
+#[serde(rename_all = "kebab-case")]
+pub enum Family {
+    /// Both IPv4 and IPv6 addresses.
+    #[default]
+    Any,
+    /// IPv4 addresses only.
+    Ipv4,
+    /// IPv6 addresses only.
+    Ipv6,
+}
+
+impl Family {
+    pub fn matches(self, addr: &IpAddr) -> bool {
+        match self {
+            Family::Any => true,
+            Family::Ipv4 => addr.is_ipv4(),
+            Family::Ipv6 => addr.is_ipv6(),
+        }
+    }
+}
+
+impl fmt::Display for Family {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Family::Any => f.write_str("any"),
+            Family::Ipv4 => f.write_str("IPv4"),
+            Family::Ipv6 => f.write_str("IPv6"),
+        }
+    }
+}
+
+impl LogRotation {
+    pub fn time_frequency(self) -> TimeFrequency {
+        match self {
+            LogRotation::Hourly => TimeFrequency::Hourly,
+            LogRotation::Daily => TimeFrequency::Daily,
+            LogRotation::Weekly => TimeFrequency::Weekly,
+            LogRotation::Monthly => TimeFrequency::Monthly,
+            LogRotation::Yearly => TimeFrequency::Yearly,
+        }
+    }
+}
+
+/// Fully resolved configuration, built from the config file and command line.
+#[derive(Debug)]
+pub struct Config {
+    pub log: LogConfig,
+    /// Address the Prometheus metrics endpoint listens on.
+    pub metrics_listen: Option<SocketAddr>,
+    pub webhooks: Vec<Webhook>,
+    pub hooks: HookConfig,
+    /// Probe the default gateway next to the targets to locate outages.
+    pub gateway: bool,
+    pub targets: Vec<Target>,
+}
+
+/// Limits applied to the command hooks of all targets.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HookConfig {
+    /// Time after which a hook is killed.
+    pub timeout: Duration,
+    /// Hooks running at the same time, further hooks wait for a free slot.
+    pub max_concurrent: usize,
+}
+
+/// HTTP endpoint notified about target state changes.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Webhook {
+    pub url: Url,
+    pub method: String,
+    pub headers: Vec<(String, String)>,
+    /// JSON body template with `{{field}}` placeholders.
+    pub body: String,
+    /// States that trigger the webhook.
+    pub events: Vec<State>,
+    pub timeout: Duration,
+    /// Longest delay between delivery retries.
+    pub max_backoff: Duration,
+    /// Alerts kept while the endpoint is unreachable, the oldest are dropped first.
+    pub queue_size: usize,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct LogConfig {
+    /// Path of the log file.
+    pub file: PathBuf,
+    /// How often the log file is rotated.
+    pub rotation: LogRotation,
+    /// Number of rotated files to keep.
+    pub keep: usize,
+}
+
+/// A single host to monitor together with its probe settings.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Target {
+    pub address: Address,
+    pub label: Option<String>,
+    pub interval: Duration,
+    pub socket_type: Socket,
+    pub timeout: Duration,
+    /// Upper bound for how long a resolved address is used before the
+    /// hostname is looked up again, shorter record TTLs take precedence.
+    pub resolve_interval: Duration,
+    /// Address family to probe.
+    pub family: Family,
+    /// Probe every resolved address of a hostname instead of just one.
+    pub all_addresses: bool,
+    /// Level successful replies are logged at.
+    pub reply_level: ReplyLevel,
+    pub stats: StatsConfig,
+    /// Consecutive failed probes before the target is considered down.
+    pub down_after: NonZeroU32,
+    /// Consecutive successful probes before the target is considered up again.
+    pub up_after: NonZeroU32,
+    pub hooks: Hooks,
+    /// Status codes HTTP probes accept, any 2xx or 3xx status if empty.
+    pub expect_status: Vec<u16>,
+    /// Text the body of HTTP responses has to contain.
+    pub expect_body: Option<String>,
+    /// Sorted answers DNS probes expect, any answers if empty.
+    pub expect_answers: Vec<String>,
+    /// Datagram UDP probes send.
+    pub payload: Option<String>,
+    /// Text UDP replies have to contain, replies have to echo the payload if unset.
+    pub expect_reply: Option<String>,
+    /// TLS probes degrade the target once the certificate expires within this period.
+    pub cert_expiry: Duration,
+    /// Time to live or hop limit of echo requests, the system default if unset.
+    pub ttl: Option<NonZeroU8>,
+    /// Payload size of echo requests in bytes.
+    pub size: usize,
+    /// Bytes the echo request payload is filled with.
+    pub pattern: Option<Pattern>,
+    /// Forbid fragmenting echo requests on the way.
+    pub dont_fragment: bool,
+    /// Probes sent to every address on each tick.
+    pub burst: NonZeroU32,
+    /// Time between the probes of a burst.
+    pub burst_gap: Duration,
+    /// Discover the path MTU of ICMP targets.
+    pub pmtu: bool,
+    /// Time between path MTU discoveries.
+    pub pmtu_interval: Duration,
+    /// Trace the route when an outage starts and ends.
+    pub traceroute: bool,
+    /// Probe every hop on the route each interval, like `mtr`.
+    pub mtr: bool,
+    /// The target is the default gateway, outages of the others are compared
+    /// against it.
+    pub gateway: bool,
+    pub binding: Binding,
+}
+
+/// Interface, source address and firewall mark probes are sent with, the
+/// kernel's routing decides where they leave if unset.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Binding {
+    /// Interface the probes are bound to with `SO_BINDTODEVICE`.
+    pub interface: Option<String>,
+    /// Local address the probes are sent from.
+    pub source: Option<IpAddr>,
+    /// Firewall mark set with `SO_MARK`, for policy routing.
+    pub fwmark: Option<u32>,
+}
+
+/// Fill pattern of echo request payloads, written in hex like `ff00`.
+#[derive(Deserialize, Clone, Debug, PartialEq, Eq, Hash)]
+#[serde(try_from = "String")]
+pub struct Pattern(Vec<u8>);
+
+impl Pattern {
+    pub fn bytes(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl FromStr for Pattern {
+    type Err = String;
+
+    fn from_str(pattern_str: &str) -> Result<Self, Self::Err> {
+        if !pattern_str.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+            return Err("expected hex digits".to_string());
+        }
+
+        if pattern_str.is_empty() || !pattern_str.len().is_multiple_of(2) {
+            return Err("expected an even number of hex digits".to_string());
+        }
+
+        let bytes = (0..pattern_str.len())
+            .step_by(2)
+            .map(|idx| u8::from_str_radix(&pattern_str[idx..idx + 2], 16))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|err| err.to_string())?;
+
+        if bytes.len() > MAX_PATTERN_SIZE {
+            return Err(format!("at most {MAX_PATTERN_SIZE} bytes are allowed"));
+        }
+
+        Ok(Pattern(bytes))
+    }
+}
+
+impl TryFrom<String> for Pattern {
+    type Error = String;
+
+    fn try_from(pattern: String) -> Result<Self, Self::Error> {
+        pattern.parse()
+    }
+}
+
+/// Shell commands run when a target changes to the corresponding state.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Hooks {
+    pub on_up: Option<String>,
+    pub on_degraded: Option<String>,
+    pub on_down: Option<String>,
+}
+
+impl Hooks {
+    pub fn command(&self, state: State) -> Option<&str> {
+        match state {
+            State::Up => self.on_up.as_deref(),
+            State::Degraded => self.on_degraded.as_deref(),
+            State::Down => self.on_down.as_deref(),
+        }
+    }
+
+    /// States that have a hook.
+    pub fn states(&self) -> Vec<State> {
+        [State::Up, State::Degraded, State::Down]
+            .into_iter()
+            .filter(|&state| self.command(state).is_some())
+            .collect()
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct StatsConfig {
+    /// How often the statistics summary is logged.
+    pub interval: Duration,
+    /// Rolling windows the statistics are computed over.
+    pub windows: Vec<Duration>,
+}
+
+impl StatsConfig {
+    /// How long samples have to be kept to cover every window.
+    pub fn retention(&self) -> Duration {
+        self.windows.iter().copied().max().unwrap_or_default()
+    }
+}
+
+/// A host together with the way it is probed, written as `host` for ICMP
+/// echo or as a URL like `tcp://host:port` for the other probes.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Address {
+    pub host: Host,
+    pub probe: Probe,
+}
+
+/// Kind of probe sent to a target.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Probe {
+    /// ICMP echo request.
+    Icmp,
+    /// TCP handshake with the port.
+    Tcp { port: u16 },
+    /// UDP datagram to the port, answered by an echo or an expected reply.
+    Udp { port: u16 },
+    /// TLS handshake with the port, verifying the certificate.
+    Tls { port: u16 },
+    /// HTTP GET request of the URL.
+    Http { url: Url },
+    /// DNS query for the name, sent to the port of the target host.
+    Dns {
+        port: u16,
+        name: String,
+        record_type: RecordType,
+    },
+}
+
+impl Probe {
+    /// Describes a failed probe, as in "failed to connect to".
+    pub fn action(&self) -> &'static str {
+        match self {
+            Probe::Icmp => "ping",
+            Probe::Tcp { .. } => "connect to",
+            Probe::Udp { .. } => "probe",
+            Probe::Tls { .. } => "connect to",
+            Probe::Http { .. } => "request",
+            Probe::Dns { .. } => "query",
+        }
+    }
+}
+
+impl FromStr for Address {
+    type Err = String;
+
+    fn from_str(address_str: &str) -> Result<Self, Self::Err> {
+        if !address_str.contains("://") {
+            return Ok(Address {
+                host: address_str.parse()?,
+                probe: Probe::Icmp,
+            });
+        }
+
+        let url = Url::parse(address_str).map_err(|err| format!("invalid URL: {err}"))?;
+        let host = url_host(&url)?;
+
+        let probe = match url.scheme() {
+            "tcp" | "udp" | "tls" => {
+                let scheme = url.scheme();
+
+                if url.path() != "" || url.query().is_some() {
+                    return Err(format!("expected {scheme}://host:port"));
+                }
+
+                let port = url
+                    .port()
+                    .ok_or_else(|| format!("missing port, expected {scheme}://host:port"))?;
+
+                match scheme {
+                    "tcp" => Probe::Tcp { port },
+                    "udp" => Probe::Udp { port },
+                    _ => Probe::Tls { port },
+                }
+            }
+            "http" | "https" => Probe::Http { url },
+            "dns" => dns_probe(&url)?,
+            scheme => {
+                return Err(format!(
+                    "unsupported scheme {scheme}, expected tcp, udp, tls, http, https or dns"
+                ));
+            }
+        };
+
+        Ok(Address { host, probe })
+    }
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.probe {
+            Probe::Icmp => self.host.fmt(f),
+            Probe::Tcp { port } => write!(f, "tcp://{}:{}", UrlHost(&self.host), port),
+            Probe::Udp { port } => write!(f, "udp://{}:{}", UrlHost(&self.host), port),
+            Probe::Tls { port } => write!(f, "tls://{}:{}", UrlHost(&self.host), port),
+            Probe::Http { url } => url.fmt(f),
+            Probe::Dns {
+                port,
+                name,
+                record_type,
+            } => {
+                write!(f, "dns://{}", UrlHost(&self.host))?;
+
+                if *port != DEFAULT_DNS_PORT {
+                    write!(f, ":{port}")?;
+                }
+
+                write!(f, "/{name}?type={record_type}")
+            }
+        }
+    }
+}
+
+/// Parses the name and record type of `dns://server[:port]/name?type=A`.
+fn dns_probe(url: &Url) -> Result<Probe, String> {
+    const EXPECTED: &str = "expected dns://server[:port]/name?type=A";
+
+    let name = url.path().strip_prefix('/').unwrap_or_default();
+
+    if name.is_empty() {
+        return Err(format!("missing name, {EXPECTED}"));
+    }
+
+    Name::from_utf8(name).map_err(|err| format!("invalid name: {err}"))?;
+
+    let mut record_type = RecordType::A;
+
+    for (key, value) in url.query_pairs() {
+        if key != "type" {
+            return Err(format!("unknown parameter {key}, {EXPECTED}"));
+        }
+
+        record_type = value
+            .to_ascii_uppercase()
+            .parse()
+            .map_err(|_| format!("unknown record type {value}"))?;
+    }
+
+    Ok(Probe::Dns {
+        port: url.port().unwrap_or(DEFAULT_DNS_PORT),
+        name: name.to_string(),
+        record_type,
+    })
+}
+
+/// Formats a host as in URLs, with IPv6 addresses in brackets.
+struct UrlHost<'a>(&'a Host);
+
+impl fmt::Display for UrlHost<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            Host::Ip(IpAddr::V6(addr)) => write!(f, "[{addr}]"),
+            host => host.fmt(f),
+        }
+    }
+}
+
+fn url_host(url: &Url) -> Result<Host, String> {
+    match url.host() {
+        Some(url::Host::Ipv4(addr)) => Ok(Host::Ip(addr.into())),
+        Some(url::Host::Ipv6(addr)) => Ok(Host::Ip(addr.into())),
+        Some(url::Host::Domain(name)) => name.parse(),
+        None => Err("missing host".to_string()),
+    }
+}
+
+/// Either a literal IP address or a hostname that is resolved at runtime.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Host {
+    Ip(IpAddr),
+    Name(String),
+}
+
+impl FromStr for Host {
+    type Err = String;
+
+    fn from_str(host_str: &str) -> Result<Self, Self::Err> {
+        if let Ok(addr) = host_str.parse::<IpAddr>() {
+            return Ok(Host::Ip(addr));
+        }
+
+        Name::from_utf8(host_str).map_err(|err| format!("invalid hostname: {err}"))?;
+
+        Ok(Host::Name(host_str.to_string()))
+    }
+}
+
+impl fmt::Display for Host {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Host::Ip(addr) => addr.fmt(f),
+            Host::Name(name) => name.fmt(f),
+        }
+    }
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct FileConfig {
+    log: FileLog,
+    stats: FileStats,
+    metrics: FileMetrics,
+    webhooks: Vec<FileWebhook>,
+    hooks: FileHooks,
+    gateway: FileGateway,
+    defaults: FileDefaults,
+    targets: Vec<FileTarget>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct FileLog {
+    file: Option<PathBuf>,
+    rotation: Option<LogRotation>,
+    keep: Option<usize>,
+    reply_level: Option<ReplyLevel>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct FileStats {
+    #[serde(deserialize_with = "deserialize_duration")]
+    interval: Option<Duration>,
+    #[serde(deserialize_with = "deserialize_durations")]
+    windows: Option<Vec<Duration>>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct FileMetrics {
+    listen: Option<SocketAddr>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FileWebhook {
+    url: Url,
+    method: Option<String>,
+    #[serde(default)]
+    headers: BTreeMap<String, String>,
+    body: Option<String>,
+    events: Option<Vec<State>>,
+    #[serde(default, deserialize_with = "deserialize_duration")]
+    timeout: Option<Duration>,
+    #[serde(default, deserialize_with = "deserialize_duration")]
+    max_backoff: Option<Duration>,
+    queue_size: Option<NonZeroUsize>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct FileHooks {
+    #[serde(deserialize_with = "deserialize_duration")]
+    timeout: Option<Duration>,
+    max_concurrent: Option<NonZeroUsize>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct FileGateway {
+    enabled: Option<bool>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct FileDefaults {
+    #[serde(deserialize_with = "deserialize_duration")]
+    interval: Option<Duration>,
+    socket_type: Option<Socket>,
+    #[serde(deserialize_with = "deserialize_duration")]
+    timeout: Option<Duration>,
+    #[serde(deserialize_with = "deserialize_duration")]
+    resolve_interval: Option<Duration>,
+    family: Option<Family>,
+    all_addresses: Option<bool>,
+    down_after: Option<NonZeroU32>,
+    up_after: Option<NonZeroU32>,
+    on_up: Option<String>,
+    on_degraded: Option<String>,
+    on_down: Option<String>,
+    expect_status: Option<Vec<u16>>,
+    expect_body: Option<String>,
+    expect_answers: Option<Vec<String>>,
+    payload: Option<String>,
+    expect_reply: Option<String>,
+    #[serde(deserialize_with = "deserialize_duration")]
+    cert_expiry: Option<Duration>,
+    ttl: Option<NonZeroU8>,
+    size: Option<usize>,
+    pattern: Option<Pattern>,
+    dont_fragment: Option<bool>,
+    burst: Option<NonZeroU32>,
+    #[serde(deserialize_with = "deserialize_duration")]
+    burst_gap: Option<Duration>,
+    pmtu: Option<bool>,
+    #[serde(deserialize_with = "deserialize_duration")]
+    pmtu_interval: Option<Duration>,
+    traceroute: Option<bool>,
+    mtr: Option<bool>,
+    interface: Option<String>,
+    source: Option<IpAddr>,
+    fwmark: Option<u32>,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FileTarget {
+    address: String,
+    label: Option<String>,
+    #[serde(default, deserialize_with = "deserialize_duration")]
+    interval: Option<Duration>,
+    socket_type: Option<Socket>,
+    #[serde(default, deserialize_with = "deserialize_duration")]
+    timeout: Option<Duration>,
+    #[serde(default, deserialize_with = "deserialize_duration")]
+    resolve_interval: Option<Duration>,
+    family: Option<Family>,
+    all_addresses: Option<bool>,
+    down_after: Option<NonZeroU32>,
+    up_after: Option<NonZeroU32>,
+    on_up: Option<String>,
+    on_degraded: Option<String>,
+    on_down: Option<String>,
+    expect_status: Option<Vec<u16>>,
+    expect_body: Option<String>,
+    expect_answers: Option<Vec<String>>,
+    payload: Option<String>,
+    expect_reply: Option<String>,
+    #[serde(default, deserialize_with = "deserialize_duration")]
+    cert_expiry: Option<Duration>,
+    ttl: Option<NonZeroU8>,
+    size: Option<usize>,
+    pattern: Option<Pattern>,
+    dont_fragment: Option<bool>,
+    burst: Option<NonZeroU32>,
+    #[serde(default, deserialize_with = "deserialize_duration")]
+    burst_gap: Option<Duration>,
+    pmtu: Option<bool>,
+    #[serde(default, deserialize_with = "deserialize_duration")]
+    pmtu_interval: Option<Duration>,
+    traceroute: Option<bool>,
+    mtr: Option<bool>,
+    interface: Option<String>,
+    source: Option<IpAddr>,
+    fwmark: Option<u32>,
+}
+
+fn deserialize_duration<'de, D>(deserializer: D) -> Result<Option<Duration>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let value = String::deserialize(deserializer)?;
+
+    parse_nonzero_duration(&value)
+        .map(Some)
+        .map_err(serde::de::Error::custom)
+}
+
+fn deserialize_durations<'de, D>(deserializer: D) -> Result<Option<Vec<Duration>>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let values = Vec::<String>::deserialize(deserializer)?;
+
+    if values.is_empty() {
+        return Err(serde::de::Error::custom(
+            "at least one duration is required",
+        ));
+    }
+
+    values
+        .iter()
+        .map(|value| parse_nonzero_duration(value))
+        .collect::<Result<Vec<_>, _>>()
+        .map(Some)
+        .map_err(serde::de::Error::custom)
+}
+
+/// Parses a firewall mark, in decimal or in hex with a `0x` prefix like `ip rule`.
+pub fn parse_fwmark(mark_str: &str) -> Result<u32, String> {
+    match mark_str.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => mark_str.parse(),
+    }
+    .map_err(|err| err.to_string())
+}
+
+/// Parses a human readable duration, rejecting zero.
+pub fn parse_nonzero_duration(duration_str: &str) -> Result<Duration, String> {
+    let duration = parse_duration(duration_str).map_err(|err| err.to_string())?;
+
+    if duration.is_zero() {
+        return Err("duration must be greater than zero".to_string());
+    }
+
+    Ok(duration)
+}
+
+/// Builds the configuration from the optional config file, with command line
+/// flags taking precedence over values from the file. The default `gateway`
+/// is added as a target unless it is configured already or disabled.
+pub fn load(args: &Args, gateway: Option<IpAddr>) -> Result<Config, String> {
+    let file = match &args.config {
+        Some(path) => {
+            let content = fs::read_to_string(path)
+                .map_err(|err| format!("failed to read {}: {}", path.display(), err))?;
+
+            toml::from_str::<FileConfig>(&content)
+                .map_err(|err| format!("invalid config {}: {}", path.display(), err))?
+        }
+        None => FileConfig::default(),
+    };
+
+    let log = LogConfig {
+        file: file
+            .log
+            .file
+            .unwrap_or_else(|| PathBuf::from(DEFAULT_LOG_FILE)),
+        rotation: args.log_rotation.or(file.log.rotation).unwrap_or_default(),
+        keep: file.log.keep.unwrap_or(DEFAULT_LOG_KEEP),
+    };
+
+    let stats = StatsConfig {
+        interval: args
+            .stats_interval
+            .or(file.stats.interval)
+            .unwrap_or(DEFAULT_STATS_INTERVAL),
+        windows: match &args.stats_windows {
+            Some(windows) => windows.clone(),
+            None => file
+                .stats
+                .windows
+                .unwrap_or_else(|| DEFAULT_STATS_WINDOWS.to_vec()),
+        },
+    };
+
+    let mut file_targets = file.targets;
+
+    if !args.address.is_empty() {
+        let mut addresses = Vec::new();
+
+        for address in &args.address {
+            if !addresses.contains(address) {
+                addresses.push(address.clone());
+            }
+        }
+
+        file_targets = addresses
+            .into_iter()
+            .map(|address| FileTarget {
+                address: address.to_string(),
+                ..Default::default()
+            })
+            .collect();
+    }
+
+    if file_targets.is_empty() {
+        return Err("targets: at least one target is required".to_string());
+    }
+
+    let probe_gateway = !args.no_gateway && file.gateway.enabled.unwrap_or(true);
+    // a source address given to all targets limits them to its family
+    let family = match args.source.or(file.defaults.source) {
+        Some(IpAddr::V4(_)) => Family::Ipv4,
+        Some(IpAddr::V6(_)) => Family::Ipv6,
+        None => args.family().or(file.defaults.family).unwrap_or_default(),
+    };
+    let gateway = gateway
+        .filter(|addr| probe_gateway && family.matches(addr))
+        .map(|addr| Address {
+            host: Host::Ip(addr),
+            probe: Probe::Icmp,
+        });
+
+    let mut targets = Vec::with_capacity(file_targets.len());
+
+    for (idx, target) in file_targets.into_iter().enumerate() {
+        let key = format!("targets[{idx}]");
+
+        let address = target
+            .address
+            .parse::<Address>()
+            .map_err(|err| format!("{key}.address: {}: {err}", target.address))?;
+
+        if target.label.as_deref().is_some_and(str::is_empty) {
+            return Err(format!("{key}.label: must not be empty"));
+        }
+
+        let hooks = Hooks {
+            on_up: args
+                .on_up
+                .clone()
+                .or(target.on_up)
+                .or_else(|| file.defaults.on_up.clone()),
+            on_degraded: args
+                .on_degraded
+                .clone()
+                .or(target.on_degraded)
+                .or_else(|| file.defaults.on_degraded.clone()),
+            on_down: args
+                .on_down
+                .clone()
+                .or(target.on_down)
+                .or_else(|| file.defaults.on_down.clone()),
+        };
+
+        for (name, command) in [
+            ("on_up", &hooks.on_up),
+            ("on_degraded", &hooks.on_degraded),
+            ("on_down", &hooks.on_down),
+        ] {
+            if command
+                .as_deref()
+                .is_some_and(|command| command.trim().is_empty())
+            {
+                return Err(format!("{key}.{name}: must not be empty"));
+            }
+        }
+
+        let timeout = args.timeout.or(target.timeout).or(file.defaults.timeout);
+
+        let mut target = Target {
+            gateway: gateway.as_ref() == Some(&address),
+            address,
+            label: target.label,
+            interval: args
+                .interval
+                .or(target.interval)
+                .or(file.defaults.interval)
+                .unwrap_or(DEFAULT_INTERVAL),
+            socket_type: args
+                .socket_type
+                .or(target.socket_type)
+                .or(file.defaults.socket_type)
+                .unwrap_or_default(),
+            timeout: Duration::ZERO,
+            resolve_interval: args
+                .resolve_interval
+                .or(target.resolve_interval)
+                .or(file.defaults.resolve_interval)
+                .unwrap_or(DEFAULT_RESOLVE_INTERVAL),
+            family: args
+                .family()
+                .or(target.family)
+                .or(file.defaults.family)
+                .unwrap_or_default(),
+            all_addresses: args
+                .all_addresses
+                .then_some(true)
+                .or(target.all_addresses)
+                .or(file.defaults.all_addresses)
+                .unwrap_or_default(),
+            reply_level: args
+                .reply_level
+                .or(file.log.reply_level)
+                .unwrap_or_default(),
+            stats: stats.clone(),
+            down_after: args
+                .down_after
+                .or(target.down_after)
+                .or(file.defaults.down_after)
+                .unwrap_or(DEFAULT_DOWN_AFTER),
+            up_after: args
+                .up_after
+                .or(target.up_after)
+                .or(file.defaults.up_after)
+                .unwrap_or(DEFAULT_UP_AFTER),
+            hooks,
+            expect_status: args
+                .expect_status
+                .clone()
+                .or(target.expect_status)
+                .or_else(|| file.defaults.expect_status.clone())
+                .unwrap_or_default(),
+            expect_body: args
+                .expect_body
+                .clone()
+                .or(target.expect_body)
+                .or_else(|| file.defaults.expect_body.clone()),
+            expect_answers: args
+                .expect_answers
+                .clone()
+                .or(target.expect_answers)
+                .or_else(|| file.defaults.expect_answers.clone())
+                .unwrap_or_default(),
+            payload: args
+                .payload
+                .clone()
+                .or(target.payload)
+                .or_else(|| file.defaults.payload.clone()),
+            expect_reply: args
+                .expect_reply
+                .clone()
+                .or(target.expect_reply)
+                .or_else(|| file.defaults.expect_reply.clone()),
+            cert_expiry: args
+                .cert_expiry
+                .or(target.cert_expiry)
+                .or(file.defaults.cert_expiry)
+                .unwrap_or(DEFAULT_CERT_EXPIRY),
+            ttl: args.ttl.or(target.ttl).or(file.defaults.ttl),
+            size: args
+                .size
+                .or(target.size)
+                .or(file.defaults.size)
+                .unwrap_or(DEFAULT_ICMP_SIZE),
+            pattern: args
+                .pattern
+                .clone()
+                .or(target.pattern)
+                .or_else(|| file.defaults.pattern.clone()),
+            dont_fragment: args
+                .dont_fragment
+                .then_some(true)
+                .or(target.dont_fragment)
+                .or(file.defaults.dont_fragment)
+                .unwrap_or_default(),
+            burst: args
+                .burst
+                .or(target.burst)
+                .or(file.defaults.burst)
+                .unwrap_or(DEFAULT_BURST),
+            burst_gap: args
+                .burst_gap
+                .or(target.burst_gap)
+                .or(file.defaults.burst_gap)
+                .unwrap_or(DEFAULT_BURST_GAP),
+            pmtu: args
+                .pmtu
+                .then_some(true)
+                .or(target.pmtu)
+                .or(file.defaults.pmtu)
+                .unwrap_or_default(),
+            pmtu_interval: args
+                .pmtu_interval
+                .or(target.pmtu_interval)
+                .or(file.defaults.pmtu_interval)
+                .unwrap_or(DEFAULT_PMTU_INTERVAL),
+            traceroute: args
+                .no_traceroute
+                .then_some(false)
+                .or(target.traceroute)
+                .or(file.defaults.traceroute)
+                .unwrap_or(true),
+            mtr: args
+                .mtr
+                .then_some(true)
+                .or(target.mtr)
+                .or(file.defaults.mtr)
+                .unwrap_or_default(),
+            binding: Binding {
+                interface: args
+                    .interface
+                    .clone()
+                    .or(target.interface)
+                    .or_else(|| file.defaults.interface.clone()),
+                source: args.source.or(target.source).or(file.defaults.source),
+                fwmark: args.fwmark.or(target.fwmark).or(file.defaults.fwmark),
+            },
+        };
+
+        if let Some(status) = target
+            .expect_status
+            .iter()
+            .find(|status| !(100..=599).contains(*status))
+        {
+            return Err(format!(
+                "{key}.expect_status: {status} is not an HTTP status code"
+            ));
+        }
+
+        if target.expect_body.as_deref() == Some("") {
+            return Err(format!("{key}.expect_body: must not be empty"));
+        }
+
+        if target.size > MAX_ICMP_SIZE {
+            return Err(format!(
+                "{key}.size: {} bytes exceed the ICMP limit of {MAX_ICMP_SIZE}",
+                target.size
+            ));
+        }
+
+        match target.payload.as_deref().map(str::len) {
+            Some(0) => return Err(format!("{key}.payload: must not be empty")),
+            Some(len) if len > MAX_UDP_PAYLOAD => {
+                return Err(format!(
+                    "{key}.payload: {len} bytes exceed the UDP limit of {MAX_UDP_PAYLOAD}"
+                ));
+            }
+            _ => {}
+        }
+
+        if target.expect_reply.as_deref() == Some("") {
+            return Err(format!("{key}.expect_reply: must not be empty"));
+        }
+
+        if target.expect_answers.iter().any(|answer| answer.is_empty()) {
+            return Err(format!(
+                "{key}.expect_answers: must not contain empty answers"
+            ));
+        }
+
+        // compared against the sorted answers in their canonical form
+        for answer in &mut target.expect_answers {
+            if let Ok(addr) = answer.parse::<IpAddr>() {
+                *answer = addr.to_string();
+            }
+        }
+        target.expect_answers.sort();
+        target.expect_answers.dedup();
+
+        if let Some(interface) = &target.binding.interface
+            && (interface.is_empty()
+                || interface.len() > MAX_INTERFACE_LEN
+                || interface.contains(['/', ' ']))
+        {
+            return Err(format!(
+                "{key}.interface: {interface:?} is not a valid interface name"
+            ));
+        }
+
+        // only addresses of the source's family can be reached from it
+        if let Some(source) = target.binding.source {
+            let family = if source.is_ipv4() {
+                Family::Ipv4
+            } else {
+                Family::Ipv6
+            };
+
+            if target.family != Family::Any && target.family != family {
+                return Err(format!(
+                    "{key}.source: {source} is not an {} address",
+                    target.family
+                ));
+            }
+
+            target.family = family;
+        }
+
+        if let Host::Ip(addr) = &target.address.host
+            && !target.family.matches(addr)
+        {
+            return Err(format!(
+                "{key}.address: {addr} is not an {} address",
+                target.family
+            ));
+        }
+
+        // the last probe of a burst is sent this long after the first one,
+        // a span too large to represent doesn't fit either
+        let burst_span = target
+            .burst_gap
+            .checked_mul(target.burst.get() - 1)
+            .filter(|span| *span < target.interval);
+
+        let Some(burst_span) = burst_span else {
+            return Err(format!(
+                "{key}.burst: {} probes {} apart don't fit into the interval of {}",
+                target.burst,
+                format_duration(target.burst_gap),
+                format_duration(target.interval)
+            ));
+        };
+
+        // a probe has to finish before the next one is due
+        let max_timeout = target.interval - burst_span;
+
+        target.timeout = match timeout {
+            Some(timeout) if timeout > max_timeout => {
+                return Err(format!(
+                    "{key}.timeout: {} is longer than the interval of {}{}",
+                    format_duration(timeout),
+                    format_duration(target.interval),
+                    if burst_span.is_zero() {
+                        String::new()
+                    } else {
+                        format!(" minus the burst of {}", format_duration(burst_span))
+                    }
+                ));
+            }
+            Some(timeout) => timeout,
+            None => DEFAULT_TIMEOUT.min(max_timeout),
+        };
+
+        if let Some(dup) = targets.iter().position(|other: &Target| {
+            other.address == target.address && other.label == target.label
+        }) {
+            return Err(format!(
+                "{key}: duplicate of targets[{dup}], set a distinct label to probe the same address twice"
+            ));
+        }
+
+        targets.push(target);
+    }
+
+    // the gateway is only pinged with the shared settings, so it can't fail
+    // validation and doesn't run the hooks or extra probes of the targets
+    if let Some(gateway) = gateway
+        && !targets.iter().any(|target| target.gateway)
+    {
+        let interval = args
+            .interval
+            .or(file.defaults.interval)
+            .unwrap_or(DEFAULT_INTERVAL);
+
+        targets.push(Target {
+            address: gateway,
+            label: Some("gateway".to_string()),
+            interval,
+            socket_type: args
+                .socket_type
+                .or(file.defaults.socket_type)
+                .unwrap_or_default(),
+            timeout: args
+                .timeout
+                .or(file.defaults.timeout)
+                .unwrap_or(DEFAULT_TIMEOUT)
+                .min(interval),
+            resolve_interval: DEFAULT_RESOLVE_INTERVAL,
+            family: Family::Any,
+            all_addresses: false,
+            reply_level: args
+                .reply_level
+                .or(file.log.reply_level)
+                .unwrap_or_default(),
+            stats: stats.clone(),
+            down_after: args
+                .down_after
+                .or(file.defaults.down_after)
+                .unwrap_or(DEFAULT_DOWN_AFTER),
+            up_after: args
+                .up_after
+                .or(file.defaults.up_after)
+                .unwrap_or(DEFAULT_UP_AFTER),
+            hooks: Hooks::default(),
+            expect_status: Vec::new(),
+            expect_body: None,
+            expect_answers: Vec::new(),
+            payload: None,
+            expect_reply: None,
+            cert_expiry: DEFAULT_CERT_EXPIRY,
+            ttl: None,
+            size: DEFAULT_ICMP_SIZE,
+            pattern: None,
+            dont_fragment: false,
+            burst: DEFAULT_BURST,
+            burst_gap: DEFAULT_BURST_GAP,
+            pmtu: false,
+            pmtu_interval: DEFAULT_PMTU_INTERVAL,
+            traceroute: false,
+            mtr: false,
+            gateway: true,
+            binding: Binding::default(),
+        });
+    }
+
+    let mut webhooks = Vec::with_capacity(file.webhooks.len());
+
+    for (idx, webhook) in file.webhooks.into_iter().enumerate() {
+        let key = format!("webhooks[{idx}]");
+
+        validate_url(&webhook.url).map_err(|err| format!("{key}.url: {err}"))?;
+
+        let body = webhook.body.unwrap_or_else(|| DEFAULT_TEMPLATE.to_string());
+
+        if let Some(field) = placeholders(&body).find(|field| !TEMPLATE_FIELDS.contains(field)) {
+            return Err(format!(
+                "{key}.body: unknown placeholder {{{{{field}}}}}, expected one of {}",
+                TEMPLATE_FIELDS.join(", ")
+            ));
+        }
+
+        let method = webhook.method.unwrap_or_else(|| "POST".to_string());
+        validate_method(&method).map_err(|err| format!("{key}.method: {err}"))?;
+
+        for (name, value) in &webhook.headers {
+            validate_header(name, value).map_err(|err| format!("{key}.headers: {err}"))?;
+        }
+
+        let mut headers = webhook.headers.into_iter().collect::<Vec<_>>();
+
+        if !headers
+            .iter()
+            .any(|(name, _)| name.eq_ignore_ascii_case("content-type"))
+        {
+            headers.push(("Content-Type".to_string(), "application/json".to_string()));
+        }
+
+        webhooks.push(Webhook {
+            url: webhook.url,
+            method,
+            headers,
+            body,
+            events: webhook
+                .events
+                .unwrap_or_else(|| vec![State::Down, State::Up]),
+            timeout: webhook.timeout.unwrap_or(DEFAULT_WEBHOOK_TIMEOUT),
+            max_backoff: webhook.max_backoff.unwrap_or(DEFAULT_WEBHOOK_MAX_BACKOFF),
+            queue_size: webhook
+                .queue_size
+                .map_or(DEFAULT_WEBHOOK_QUEUE_SIZE, NonZeroUsize::get),
+        });
+    }
+
+    Ok(Config {
+        log,
+        metrics_listen: args.metrics_listen.or(file.metrics.listen),
+        webhooks,
+        hooks: HookConfig {
+            timeout: file.hooks.timeout.unwrap_or(DEFAULT_HOOK_TIMEOUT),
+            max_concurrent: file
+                .hooks
+                .max_concurrent
+                .map_or(DEFAULT_HOOK_MAX_CONCURRENT, NonZeroUsize::get),
+        },
+        gateway: probe_gateway,
+        targets,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn address(address_str: &str) -> Result<Address, String> {
+        address_str.parse()
+    }
+
+    #[test]
+    fn plain_hosts_are_pinged() {
+        let parsed = address("192.0.2.1").unwrap();
+        assert_eq!(parsed.host, Host::Ip("192.0.2.1".parse().unwrap()));
+        assert_eq!(parsed.probe, Probe::Icmp);
+
+        let parsed = address("example.com").unwrap();
+        assert_eq!(parsed.host, Host::Name("example.com".to_string()));
+        assert_eq!(parsed.to_string(), "example.com");
+    }
+
+    #[test]
+    fn tcp_addresses_round_trip() {
+        for address_str in [
+            "tcp://192.0.2.1:22",
+            "tcp://[2001:db8::1]:443",
+            "tcp://example.com:80",
+        ] {
+            let parsed = address(address_str).unwrap();
+            assert_eq!(parsed.probe.action(), "connect to");
+            assert_eq!(parsed.to_string(), address_str);
+        }
+
+        let parsed = address("tcp://[2001:db8::1]:443").unwrap();
+        assert_eq!(parsed.host, Host::Ip("2001:db8::1".parse().unwrap()));
+        assert_eq!(parsed.probe, Probe::Tcp { port: 443 });
+    }
+
+    #[test]
+    fn datagram_and_tls_addresses_round_trip() {
+        let parsed = address("udp://[2001:db8::1]:7").unwrap();
+
+        assert_eq!(parsed.probe, Probe::Udp { port: 7 });
+        assert_eq!(parsed.to_string(), "udp://[2001:db8::1]:7");
+        assert!(address("udp://192.0.2.1").is_err());
+
+        let parsed = address("tls://example.com:853").unwrap();
+
+        assert_eq!(parsed.probe, Probe::Tls { port: 853 });
+        assert_eq!(parsed.to_string(), "tls://example.com:853");
+    }
+
+    #[test]
+    fn http_addresses_keep_the_url() {
+        let parsed = address("https://Example.com:8443/health?full=1").unwrap();
+
+        assert_eq!(parsed.host, Host::Name("example.com".to_string()));
+        assert_eq!(parsed.to_string(), "https://example.com:8443/health?full=1");
+        assert!(matches!(parsed.probe, Probe::Http { .. }));
+    }
+
+    #[test]
+    fn dns_addresses_round_trip() {
+        for address_str in [
+            "dns://192.0.2.53/example.com?type=A",
+            "dns://[2001:db8::53]:5353/example.com?type=AAAA",
+            "dns://dns.example/example.com.?type=MX",
+        ] {
+            let parsed = address(address_str).unwrap();
+            assert_eq!(parsed.probe.action(), "query");
+            assert_eq!(parsed.to_string(), address_str);
+        }
+
+        let parsed = address("dns://192.0.2.53/example.com?type=txt").unwrap();
+        assert_eq!(parsed.host, Host::Ip("192.0.2.53".parse().unwrap()));
+        assert_eq!(
+            parsed.probe,
+            Probe::Dns {
+                port: 53,
+                name: "example.com".to_string(),
+                record_type: RecordType::TXT,
+            }
+        );
+
+        // the record type defaults to A
+        let parsed = address("dns://192.0.2.53:53/example.com").unwrap();
+        assert_eq!(parsed.to_string(), "dns://192.0.2.53/example.com?type=A");
+    }
+
+    fn load_args(args: &[&str]) -> Result<Config, String> {
+        let args = [&["pinger", "-a", "192.0.2.1"], args].concat();
+
+        load(&<Args as clap::Parser>::try_parse_from(args).unwrap(), None)
+    }
+
+    #[test]
+    fn burst_has_to_fit_into_the_interval() {
+        let config = load_args(&["-i", "1s", "--burst", "5", "--burst-gap", "200ms"]).unwrap();
+        // the default timeout is shortened to the time left after the last probe
+        assert_eq!(config.targets[0].timeout, Duration::from_millis(200));
+
+        let err = load_args(&["-i", "1s", "--burst", "6", "--burst-gap", "200ms"]).unwrap_err();
+        assert!(err.starts_with("targets[0].burst:"), "{err}");
+
+        let err = load_args(&["--burst", "4000000000", "--burst-gap", "1000years"]).unwrap_err();
+        assert!(err.starts_with("targets[0].burst:"), "{err}");
+
+        let err = load_args(&["-i", "1s", "--burst", "3", "-t", "900ms"]).unwrap_err();
+        assert_eq!(
+            err,
+            "targets[0].timeout: 900ms is longer than the interval of 1s minus the burst of 200ms"
+        );
+    }
+
+    #[test]
+    fn pmtu_discovery_is_opt_in() {
+        let target = &load_args(&[]).unwrap().targets[0];
+        assert!(!target.pmtu);
+        assert_eq!(target.pmtu_interval, DEFAULT_PMTU_INTERVAL);
+
+        let target = &load_args(&["--pmtu", "--pmtu-interval", "1h"])
+            .unwrap()
+            .targets[0];
+        assert!(target.pmtu);
+        assert_eq!(target.pmtu_interval, Duration::from_secs(60 * 60));
+    }
+
+    #[test]
+    fn traceroute_is_opt_out() {
+        assert!(load_args(&[]).unwrap().targets[0].traceroute);
+        assert!(!load_args(&["--no-traceroute"]).unwrap().targets[0].traceroute);
+    }
+
+    #[test]
+    fn gateway_is_probed_next_to_the_targets() {
+        let load_gateway = |args: &[&str], gateway: &str| {
+            let args = [&["pinger"], args].concat();
+            let args = <Args as clap::Parser>::try_parse_from(args).unwrap();
+
+            load(&args, Some(gateway.parse().unwrap())).unwrap().targets
+        };
+
+        let targets = load_gateway(&["-a", "192.0.2.1"], "192.168.1.1");
+        assert_eq!(targets.len(), 2);
+        assert!(!targets[0].gateway);
+        assert!(targets[1].gateway);
+        assert_eq!(targets[1].label.as_deref(), Some("gateway"));
+
+        // a target pinging the gateway already is used instead
+        let targets = load_gateway(&["-a", "192.0.2.1,192.168.1.1"], "192.168.1.1");
+        assert_eq!(targets.len(), 2);
+        assert!(targets[1].gateway && targets[1].label.is_none());
+
+        assert_eq!(
+            load_gateway(&["-a", "192.0.2.1", "--no-gateway"], "192.168.1.1").len(),
+            1
+        );
+        assert_eq!(
+            load_gateway(&["-6", "-a", "2001:db8::1"], "192.168.1.1").len(),
+            1
+        );
+        assert_eq!(
+            load_gateway(
+                &["-a", "2001:db8::1", "--source", "2001:db8::10"],
+                "192.168.1.1"
+            )
+            .len(),
+            1
+        );
+
+        // only the shared settings apply to the gateway
+        let targets = load_gateway(
+            &[
+                "-a",
+                "192.0.2.1",
+                "--interface",
+                "wwan0",
+                "--on-down",
+                "true",
+                "--mtr",
+                "--burst",
+                "3",
+                "-i",
+                "2s",
+            ],
+            "192.168.1.1",
+        );
+        let gateway = &targets[1];
+        assert_eq!(gateway.binding, Binding::default());
+        assert_eq!(gateway.hooks, Hooks::default());
+        assert!(!gateway.mtr);
+        assert_eq!(gateway.burst, DEFAULT_BURST);
+        assert_eq!(gateway.interval, Duration::from_secs(2));
+        assert_eq!(gateway.timeout, Duration::from_secs(2));
+    }
+
+    #[test]
+    fn probes_can_be_bound() {
+        let target = &load_args(&[
+            "--interface",
+            "wwan0",
+            "--source",
+            "192.0.2.10",
+            "--fwmark",
+            "0x100",
+        ])
+        .unwrap()
+        .targets[0];
+        assert_eq!(target.binding.interface.as_deref(), Some("wwan0"));
+        assert_eq!(target.binding.fwmark, Some(256));
+        assert_eq!(target.family, Family::Ipv4);
+
+        let err = load_args(&["--source", "2001:db8::10"]).unwrap_err();
+        assert_eq!(err, "targets[0].address: 192.0.2.1 is not an IPv6 address");
+
+        let err = load_args(&["--interface", "a-very-long-name0"]).unwrap_err();
+        assert!(err.starts_with("targets[0].interface:"), "{err}");
+
+        assert_eq!(parse_fwmark("42"), Ok(42));
+        assert!(parse_fwmark("0xfg").is_err());
+    }
+
+    #[test]
+    fn patterns_are_hex() {
+        assert_eq!("ff00".parse::<Pattern>().unwrap().bytes(), [0xff, 0x00]);
+        assert_eq!("aB".parse::<Pattern>().unwrap().bytes(), [0xab]);
+
+        for pattern in ["", "f", "fg", "+f", "ä0", &"00".repeat(17)] {
+            assert!(pattern.parse::<Pattern>().is_err(), "{pattern:?}");
+        }
+    }
+
+    #[test]
+    fn invalid_addresses_are_rejected() {
+        assert!(address("tcp://example.com").is_err());
+        assert!(address("tcp://example.com:80/path").is_err());
+        assert!(address("gopher://example.com:70").is_err());
+        assert!(address("dns://192.0.2.53").is_err());
+        assert!(address("dns://192.0.2.53/example.com?type=BOGUS").is_err());
+        assert!(address("dns://192.0.2.53/example.com?class=IN").is_err());
+        assert!(address("exa mple.com").is_err());
+    }
+}
@@ -0,0 +1,154 @@
+use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+    net::SocketAddr,
+    time::Instant,
+};
+
+use hickory_resolver::{
+    Name,
+    proto::{
+        op::{Edns, Message, MessageType, OpCode, Query, ResponseCode},
+        rr::RecordType,
+    },
+};
+use tokio::time::timeout;
+
+use crate::{
+    config::{Binding, Target},
+    probe::{Failure, Reply},
+    tcp, udp,
+};
+
+/// UDP payload size advertised with EDNS, small enough to avoid fragmentation.
+const MAX_PAYLOAD: u16 = 1232;
+
+/// Sends a single recursive query to the server and checks the response. The
+/// query is neither cached nor retried, so every tick measures the server.
+pub async fn query(
+    target: &Target,
+    server: SocketAddr,
+    name: &str,
+    record_type: RecordType,
+) -> Result<Reply, Failure> {
+    let name = Name::from_utf8(name).map_err(|err| Failure::new("query", err))?;
+    let id = RandomState::new().build_hasher().finish() as u16;
+
+    let mut edns = Edns::new();
+    edns.set_max_payload(MAX_PAYLOAD);
+
+    let mut request = Message::new();
+    request
+        .set_id(id)
+        .set_message_type(MessageType::Query)
+        .set_op_code(OpCode::Query)
+        .set_recursion_desired(true)
+        .add_query(Query::query(name, record_type))
+        .set_edns(edns);
+
+    let request = request.to_vec().map_err(|err| Failure::new("query", err))?;
+    let start = Instant::now();
+
+    let response = timeout(
+        target.timeout,
+        exchange(server, &target.binding, &request, id),
+    )
+    .await
+    .map_err(|_| Failure::new("timeout", "query timed out"))??;
+
+    let rtt = start.elapsed();
+
+    match response.response_code() {
+        ResponseCode::NoError => {}
+        ResponseCode::ServFail => return Err(Failure::new("servfail", "server failure")),
+        ResponseCode::NXDomain => return Err(Failure::new("nxdomain", "name does not exist")),
+        code => {
+            return Err(Failure::new(
+                "rcode",
+                format!("unexpected response code {}", rcode_name(code)),
+            ));
+        }
+    }
+
+    // answers of other types, like the CNAMEs leading to them, are ignored
+    let mut answers = response
+        .answers()
+        .iter()
+        .filter(|record| record.record_type() == record_type)
+        .map(|record| record.data().to_string())
+        .collect::<Vec<_>>();
+    answers.sort();
+    answers.dedup();
+
+    if !target.expect_answers.is_empty() && answers != target.expect_answers {
+        return Err(Failure::new(
+            "answer",
+            format!(
+                "unexpected answers [{}], expected [{}]",
+                answers.join(", "),
+                target.expect_answers.join(", ")
+            ),
+        ));
+    }
+
+    let detail = if answers.is_empty() {
+        "NOERROR, no answers".to_string()
+    } else {
+        format!("NOERROR, answers {}", answers.join(", "))
+    };
+
+    Ok(Reply {
+        rtt,
+        detail: Some(detail),
+        warning: None,
+        phases: Vec::new(),
+    })
+}
+
+/// Sends the request and waits for the response with the same id, datagrams
+/// from other addresses are dropped by the connected socket.
+async fn exchange(
+    server: SocketAddr,
+    binding: &Binding,
+    request: &[u8],
+    id: u16,
+) -> Result<Message, Failure> {
+    let io_failure = |err: std::io::Error| Failure::new(tcp::error_kind(&err), err);
+
+    let socket = udp::connect(server, binding).await.map_err(io_failure)?;
+    socket.send(request).await.map_err(io_failure)?;
+
+    let mut buf = vec![0; usize::from(MAX_PAYLOAD)];
+
+    loop {
+        let len = socket.recv(&mut buf).await.map_err(io_failure)?;
+
+        // stray or malformed datagrams are skipped like late responses
+        if let Ok(response) = Message::from_vec(&buf[..len])
+            && response.id() == id
+            && response.message_type() == MessageType::Response
+        {
+            return Ok(response);
+        }
+    }
+}
+
+/// Returns the mnemonic of a response code, like `REFUSED`.
+fn rcode_name(code: ResponseCode) -> String {
+    match code {
+        ResponseCode::Unknown(value) => value.to_string(),
+        code => format!("{code:?}").to_uppercase(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rcode_names_are_mnemonics() {
+        assert_eq!(rcode_name(ResponseCode::Refused), "REFUSED");
+        assert_eq!(rcode_name(ResponseCode::NotImp), "NOTIMP");
+        assert_eq!(rcode_name(ResponseCode::Unknown(3841)), "3841");
+    }
+}
@@ -0,0 +1,120 @@
+use std::{
+    collections::BTreeMap,
+    fmt,
+    sync::{Arc, Mutex},
+};
+
+use crate::metrics::TargetKey;
+
+/// Where an outage is, judged by the probes that fail together with it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Fault {
+    /// The default gateway doesn't reply either.
+    Lan,
+    /// The gateway replies but no other target does.
+    Upstream,
+    /// Other targets still reply.
+    Remote,
+}
+
+impl fmt::Display for Fault {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Fault::Lan => f.write_str("local LAN"),
+            Fault::Upstream => f.write_str("ISP/upstream"),
+            Fault::Remote => f.write_str("remote host only"),
+        }
+    }
+}
+
+/// Latest outcome of every target, shared by the probes to locate outages.
+#[derive(Clone, Default)]
+pub struct Health {
+    targets: Arc<Mutex<BTreeMap<TargetKey, Status>>>,
+}
+
+#[derive(Clone, Copy)]
+struct Status {
+    gateway: bool,
+    /// The last tick got no reply at all.
+    failing: bool,
+}
+
+impl Health {
+    pub fn update(&self, key: &TargetKey, gateway: bool, failing: bool) {
+        self.targets
+            .lock()
+            .unwrap()
+            .insert(key.clone(), Status { gateway, failing });
+    }
+
+    pub fn remove(&self, key: &TargetKey) {
+        self.targets.lock().unwrap().remove(key);
+    }
+
+    /// Locates the outage of the target by comparing it with the gateway and
+    /// the other targets. Returns `None` if there is nothing to tell an ISP
+    /// outage from one of the host apart with.
+    pub fn locate(&self, key: &TargetKey) -> Option<Fault> {
+        let targets = self.targets.lock().unwrap();
+
+        if targets.get(key)?.gateway {
+            return Some(Fault::Lan);
+        }
+
+        let mut gateway_failing = None;
+        let (mut others, mut reachable) = (0, 0);
+
+        for (_, status) in targets.iter().filter(|(other, _)| *other != key) {
+            if status.gateway {
+                gateway_failing = Some(status.failing);
+            } else {
+                others += 1;
+                reachable += usize::from(!status.failing);
+            }
+        }
+
+        match gateway_failing {
+            _ if reachable > 0 => Some(Fault::Remote),
+            Some(true) => Some(Fault::Lan),
+            Some(false) if others > 0 => Some(Fault::Upstream),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Address;
+
+    fn key(address: &str) -> TargetKey {
+        TargetKey::from_parts(&address.parse::<Address>().unwrap(), None)
+    }
+
+    #[test]
+    fn outages_are_located_by_the_probes_failing_with_them() {
+        let health = Health::default();
+        let (gateway, remote, other) = (key("192.168.1.1"), key("192.0.2.1"), key("192.0.2.2"));
+
+        // a lone target can't be told apart from its ISP
+        health.update(&gateway, true, false);
+        health.update(&remote, false, true);
+        assert_eq!(health.locate(&remote), None);
+
+        health.update(&other, false, false);
+        assert_eq!(health.locate(&remote), Some(Fault::Remote));
+
+        health.update(&other, false, true);
+        assert_eq!(health.locate(&remote), Some(Fault::Upstream));
+
+        health.update(&gateway, true, true);
+        assert_eq!(health.locate(&remote), Some(Fault::Lan));
+        assert_eq!(health.locate(&gateway), Some(Fault::Lan));
+
+        // without a gateway only remote outages are certain
+        health.remove(&gateway);
+        assert_eq!(health.locate(&remote), None);
+        assert_eq!(health.locate(&key("192.0.2.3")), None);
+    }
+}
@@ -0,0 +1,101 @@
+use std::{
+    fs,
+    net::{IpAddr, Ipv4Addr},
+};
+
+/// IPv4 routing table of the kernel.
+const ROUTES: &str = "/proc/net/route";
+/// Route flags from `linux/route.h`.
+const RTF_UP: u32 = 0x1;
+const RTF_GATEWAY: u32 = 0x2;
+
+/// Returns the gateway of the IPv4 default route with the lowest metric,
+/// the one the kernel sends packets to.
+pub fn default_gateway() -> Result<Option<IpAddr>, String> {
+    let table =
+        fs::read_to_string(ROUTES).map_err(|err| format!("failed to read {ROUTES}: {err}"))?;
+
+    parse(&table)
+}
+
+/// Parses the routing table, a header line followed by a line per route with
+/// addresses in hex as they are stored in memory.
+fn parse(table: &str) -> Result<Option<IpAddr>, String> {
+    let mut best = None;
+
+    for line in table.lines().skip(1) {
+        let fields = line.split_whitespace().collect::<Vec<_>>();
+
+        let [_, destination, gateway, flags, _, _, metric, mask, ..] = fields[..] else {
+            return Err(format!("malformed route: {line}"));
+        };
+
+        let hex = |field: &str| {
+            u32::from_str_radix(field, 16).map_err(|err| format!("malformed route: {line}: {err}"))
+        };
+        let metric = metric
+            .parse::<u32>()
+            .map_err(|err| format!("malformed route: {line}: {err}"))?;
+
+        if hex(destination)? != 0 || hex(mask)? != 0 {
+            continue;
+        }
+
+        if hex(flags)? & (RTF_UP | RTF_GATEWAY) != RTF_UP | RTF_GATEWAY {
+            continue;
+        }
+
+        if best.is_none_or(|(best_metric, _)| metric < best_metric) {
+            let gateway = Ipv4Addr::from(hex(gateway)?.to_ne_bytes());
+            best = Some((metric, IpAddr::V4(gateway)));
+        }
+    }
+
+    Ok(best.map(|(_, gateway)| gateway))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Writes an address the way the kernel prints it on this host.
+    fn hex(addr: [u8; 4]) -> String {
+        format!("{:08X}", u32::from_ne_bytes(addr))
+    }
+
+    #[test]
+    fn default_route_with_the_lowest_metric_wins() {
+        let header =
+            "Iface\tDestination\tGateway \tFlags\tRefCnt\tUse\tMetric\tMask\t\tMTU\tWindow\tIRTT";
+        let route = |iface: &str, destination: [u8; 4], gateway: [u8; 4], metric: u32| {
+            let mask = if destination == [0; 4] {
+                [0; 4]
+            } else {
+                [255, 255, 255, 0]
+            };
+
+            format!(
+                "{iface}\t{}\t{}\t0003\t0\t0\t{metric}\t{}\t0\t0\t0",
+                hex(destination),
+                hex(gateway),
+                hex(mask)
+            )
+        };
+
+        let table = [
+            header.to_string(),
+            route("eth0", [192, 168, 1, 0], [192, 168, 1, 2], 0),
+            route("wwan0", [0; 4], [10, 64, 0, 1], 700),
+            route("eth0", [0; 4], [192, 168, 1, 1], 100),
+        ]
+        .join("\n");
+
+        assert_eq!(
+            parse(&table).unwrap(),
+            Some(IpAddr::V4(Ipv4Addr::new(192, 168, 1, 1)))
+        );
+
+        assert_eq!(parse(header).unwrap(), None);
+        assert!(parse(&format!("{header}\neth0\t00000000")).is_err());
+    }
+}
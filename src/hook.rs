@@ -0,0 +1,276 @@
+use std::{
+    process::Stdio,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use humantime::format_duration;
+use tokio::{io::AsyncReadExt, process::Command, sync::Semaphore, time::timeout};
+use tracing::{Instrument, Span, info, warn};
+
+use crate::{
+    alert::{Event, EventFilter, TEMPLATE_FIELDS},
+    config::{HookConfig, Hooks},
+    state::State,
+};
+
+/// Runs the command hooks of targets, limiting how many run at once.
+#[derive(Clone)]
+pub struct HookRunner {
+    timeout: Duration,
+    permits: Arc<Semaphore>,
+    /// Shared by all targets so it outlives restarted probes.
+    filter: Arc<Mutex<EventFilter>>,
+}
+
+impl HookRunner {
+    pub fn new(config: &HookConfig) -> Self {
+        Self {
+            timeout: config.timeout,
+            permits: Arc::new(Semaphore::new(config.max_concurrent)),
+            filter: Arc::default(),
+        }
+    }
+
+    /// Starts the hook for the state the target changed to, if one is
+    /// configured. Hooks over the concurrency limit wait for a free slot.
+    pub fn run(&self, hooks: &Hooks, event: Event) {
+        if !self.filter.lock().unwrap().accept(&hooks.states(), &event) {
+            return;
+        }
+
+        let Some(command) = hooks.command(event.state) else {
+            return;
+        };
+
+        let name = hook_name(event.state);
+        let command = command.to_string();
+        let runner = self.clone();
+
+        tokio::spawn(
+            async move {
+                let Ok(_permit) = runner.permits.acquire().await else {
+                    return;
+                };
+
+                runner.execute(name, &command, &event).await;
+            }
+            .instrument(Span::current()),
+        );
+    }
+
+    async fn execute(&self, name: &str, command: &str, event: &Event) {
+        let mut cmd = shell(command);
+
+        for field in TEMPLATE_FIELDS {
+            cmd.env(
+                format!("PINGER_{}", field.to_ascii_uppercase()),
+                event.field(field),
+            );
+        }
+
+        let child = cmd
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn();
+
+        let mut child = match child {
+            Ok(child) => child,
+            Err(err) => {
+                warn!("Failed to run {} hook, error: {}", name, err);
+                return;
+            }
+        };
+
+        let (Some(mut stdout), Some(mut stderr)) = (child.stdout.take(), child.stderr.take())
+        else {
+            return;
+        };
+
+        let mut out = Vec::new();
+        let mut err = Vec::new();
+
+        // output is collected even if the hook times out, it usually tells why
+        let status = timeout(self.timeout, async {
+            let (status, _, _) = tokio::join!(
+                child.wait(),
+                stdout.read_to_end(&mut out),
+                stderr.read_to_end(&mut err)
+            );
+            status
+        })
+        .await;
+
+        for line in String::from_utf8_lossy(&out).lines() {
+            info!("{} hook: {}", name, line);
+        }
+
+        for line in String::from_utf8_lossy(&err).lines() {
+            warn!("{} hook: {}", name, line);
+        }
+
+        match status {
+            Ok(Ok(status)) if status.success() => info!("The {} hook finished", name),
+            Ok(Ok(status)) => warn!("The {} hook failed, {}", name, status),
+            Ok(Err(err)) => warn!("Failed to wait for {} hook, error: {}", name, err),
+            Err(_) => {
+                let _ = child.kill().await;
+                warn!(
+                    "The {} hook timed out after {} and was killed",
+                    name,
+                    format_duration(self.timeout)
+                );
+            }
+        }
+    }
+}
+
+fn hook_name(state: State) -> &'static str {
+    match state {
+        State::Up => "on_up",
+        State::Degraded => "on_degraded",
+        State::Down => "on_down",
+    }
+}
+
+#[cfg(unix)]
+fn shell(command: &str) -> Command {
+    let mut cmd = Command::new("sh");
+    cmd.arg("-c").arg(command);
+    cmd
+}
+
+#[cfg(not(unix))]
+fn shell(command: &str) -> Command {
+    let mut cmd = Command::new("cmd");
+    cmd.arg("/C").arg(command);
+    cmd
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::{fs, path::PathBuf, time::Instant};
+
+    use super::*;
+    use crate::fault::Fault;
+
+    fn runner(timeout: Duration, max_concurrent: usize) -> HookRunner {
+        HookRunner::new(&HookConfig {
+            timeout,
+            max_concurrent,
+        })
+    }
+
+    fn event(target: &str) -> Event {
+        Event {
+            target: target.to_string(),
+            label: Some("core".to_string()),
+            state: State::Down,
+            previous: State::Up,
+            duration: Duration::from_secs(90),
+            lost: 3,
+            fault: Some(Fault::Upstream),
+            at: std::time::SystemTime::now(),
+        }
+    }
+
+    /// An empty directory for the files the hooks of a test write.
+    fn scratch(test: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("pinger-{}-{test}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[tokio::test]
+    async fn hooks_get_the_event_in_their_environment() {
+        let dir = scratch("hook-env");
+        let out = dir.join("out");
+        let command = format!(
+            "echo \"$PINGER_TARGET $PINGER_LABEL $PINGER_STATE $PINGER_PREVIOUS_STATE \
+             $PINGER_DURATION_SECONDS $PINGER_LOST $PINGER_FAULT\" > {}",
+            out.display()
+        );
+
+        runner(Duration::from_secs(5), 1)
+            .execute("on_down", &command, &event("192.0.2.1"))
+            .await;
+
+        assert_eq!(
+            fs::read_to_string(&out).unwrap(),
+            "192.0.2.1 core down up 90 3 ISP/upstream\n"
+        );
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn hooks_are_killed_after_the_timeout() {
+        let dir = scratch("hook-timeout");
+        let pid = dir.join("pid");
+        let command = format!("echo $$ > {}; exec sleep 10", pid.display());
+        let start = Instant::now();
+
+        runner(Duration::from_millis(200), 1)
+            .execute("on_down", &command, &event("192.0.2.1"))
+            .await;
+
+        assert!(start.elapsed() < Duration::from_secs(5));
+
+        let pid = fs::read_to_string(&pid).unwrap().trim().parse().unwrap();
+        // SAFETY: signal 0 only checks that the process exists
+        assert_eq!(unsafe { libc::kill(pid, 0) }, -1, "hook {pid} still runs");
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn hooks_over_the_limit_wait() {
+        let dir = scratch("hook-limit");
+        let hooks = Hooks {
+            // counts the hooks running at the same time as this one
+            on_down: Some(format!(
+                "cd {}; touch running.$$; ls running.* | wc -l >> counts; sleep 0.2; \
+                 rm running.$$; touch done.$$",
+                dir.display()
+            )),
+            ..Hooks::default()
+        };
+        let runner = runner(Duration::from_secs(5), 2);
+
+        for target in ["192.0.2.1", "192.0.2.2", "192.0.2.3", "192.0.2.4"] {
+            runner.run(&hooks, event(target));
+        }
+
+        let done = || {
+            fs::read_dir(&dir)
+                .unwrap()
+                .filter(|entry| {
+                    entry
+                        .as_ref()
+                        .unwrap()
+                        .file_name()
+                        .to_string_lossy()
+                        .starts_with("done.")
+                })
+                .count()
+        };
+        let start = Instant::now();
+        while done() < 4 {
+            assert!(
+                start.elapsed() < Duration::from_secs(10),
+                "hooks didn't finish"
+            );
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+
+        let counts = fs::read_to_string(dir.join("counts")).unwrap();
+        let counts = counts
+            .lines()
+            .map(|count| count.trim().parse::<usize>().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(counts.len(), 4);
+        assert!(counts.iter().all(|&count| count <= 2), "{counts:?}");
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
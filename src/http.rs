@@ -0,0 +1,621 @@
+use std::{
+    net::{IpAddr, SocketAddr},
+    time::{Duration, Instant},
+};
+
+use hickory_resolver::TokioResolver;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::TcpStream,
+    time::timeout,
+};
+use url::Url;
+
+use crate::{
+    config::{Binding, Target},
+    probe::{Failure, Reply},
+    tcp, tls,
+};
+
+const USER_AGENT: &str = concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"));
+
+/// Largest response body that is read, the rest is discarded.
+const MAX_BODY_SIZE: usize = 1024 * 1024;
+
+/// A minimal HTTP/1.1 request, sent over a fresh connection.
+pub struct Request<'a> {
+    pub method: &'a str,
+    pub url: &'a Url,
+    pub headers: &'a [(String, String)],
+    pub body: &'a [u8],
+    pub timeout: Duration,
+}
+
+pub struct Response {
+    pub status: u16,
+    pub body: Vec<u8>,
+}
+
+/// Checks that the URL can be requested with [`send`].
+pub fn validate_url(url: &Url) -> Result<(), String> {
+    match url.scheme() {
+        "http" | "https" => {}
+        scheme => {
+            return Err(format!(
+                "unsupported scheme {scheme}, expected http or https"
+            ));
+        }
+    }
+
+    if url.host_str().is_none() {
+        return Err("missing host".to_string());
+    }
+
+    Ok(())
+}
+
+/// Checks that the method is a valid HTTP token.
+pub fn validate_method(method: &str) -> Result<(), String> {
+    if !is_token(method) {
+        return Err(format!("invalid method {method:?}"));
+    }
+
+    Ok(())
+}
+
+/// Checks that the header can be written to a request head as is.
+pub fn validate_header(name: &str, value: &str) -> Result<(), String> {
+    if !is_token(name) {
+        return Err(format!("invalid header name {name:?}"));
+    }
+
+    if value.chars().any(|ch| ch.is_control() && ch != '\t') {
+        return Err(format!("invalid value of header {name}"));
+    }
+
+    Ok(())
+}
+
+/// Whether the value is a token as defined by RFC 9110.
+fn is_token(value: &str) -> bool {
+    !value.is_empty()
+        && value
+            .bytes()
+            .all(|byte| byte.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&byte))
+}
+
+/// Requests the URL from the address and checks the response status and body
+/// against the expectations of the target.
+pub async fn probe(
+    target: &Target,
+    url: &Url,
+    addr: IpAddr,
+    dns: Option<Duration>,
+) -> Result<Reply, Failure> {
+    let port = url.port_or_known_default().unwrap_or(80);
+    let start = Instant::now();
+
+    let (response, timings) = send_to(
+        SocketAddr::new(addr, port),
+        &target.binding,
+        &Request {
+            method: "GET",
+            url,
+            headers: &[],
+            body: &[],
+            timeout: target.timeout,
+        },
+    )
+    .await?;
+
+    let rtt = start.elapsed();
+
+    let status_ok = if target.expect_status.is_empty() {
+        (200..400).contains(&response.status)
+    } else {
+        target.expect_status.contains(&response.status)
+    };
+
+    if !status_ok {
+        return Err(Failure::new(
+            "status",
+            format!("unexpected status {}", response.status),
+        ));
+    }
+
+    if let Some(expected) = &target.expect_body
+        && !response
+            .body
+            .windows(expected.len())
+            .any(|window| window == expected.as_bytes())
+    {
+        return Err(Failure::new(
+            "body",
+            format!("response body doesn't contain {expected:?}"),
+        ));
+    }
+
+    let mut phases = dns.map(|dns| ("dns", dns)).into_iter().collect::<Vec<_>>();
+    phases.push(("connect", timings.connect));
+    if url.scheme() == "https" {
+        phases.push(("tls", timings.tls));
+    }
+    phases.push(("first_byte", timings.first_byte));
+
+    Ok(Reply {
+        rtt,
+        detail: Some(format!("status {}", response.status)),
+        warning: None,
+        phases,
+    })
+}
+
+/// Durations of the phases of a request.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Timings {
+    pub connect: Duration,
+    /// TLS handshake, zero for plain HTTP.
+    pub tls: Duration,
+    /// From sending the request until the first byte of the response.
+    pub first_byte: Duration,
+}
+
+/// Sends the request and reads the whole response within the request timeout.
+pub async fn send(resolver: &TokioResolver, request: &Request<'_>) -> Result<Response, String> {
+    timeout(request.timeout, send_inner(resolver, request))
+        .await
+        .map_err(|_| "request timed out".to_string())?
+}
+
+async fn send_inner(resolver: &TokioResolver, request: &Request<'_>) -> Result<Response, String> {
+    let url = request.url;
+    let host = url.host_str().ok_or("missing host")?;
+    let host = host.trim_matches(['[', ']']);
+    let port = url.port_or_known_default().ok_or("missing port")?;
+
+    let addresses = match host.parse::<IpAddr>() {
+        Ok(addr) => vec![addr],
+        Err(_) => resolver
+            .lookup_ip(host)
+            .await
+            .map_err(|err| format!("failed to resolve {host}: {err}"))?
+            .iter()
+            .collect(),
+    };
+
+    let stream = connect(&addresses, port, request.timeout).await?;
+    let (response, _) = exchange(stream, request)
+        .await
+        .map_err(|err| err.to_string())?;
+
+    Ok(response)
+}
+
+/// Sends the request to the given address instead of resolving the URL host,
+/// from a socket with the binding applied, and measures the phases of the
+/// request.
+pub async fn send_to(
+    addr: SocketAddr,
+    binding: &Binding,
+    request: &Request<'_>,
+) -> Result<(Response, Timings), Failure> {
+    timeout(request.timeout, async {
+        let start = Instant::now();
+        let stream = tcp::open(addr, binding)
+            .await
+            .map_err(|err| Failure::new(tcp::error_kind(&err), err))?;
+        let connect = start.elapsed();
+
+        let (response, timings) = exchange(stream, request).await?;
+
+        Ok((response, Timings { connect, ..timings }))
+    })
+    .await
+    .map_err(|_| Failure::new("timeout", "request timed out"))?
+}
+
+/// Connects to the first address that accepts the connection. Every address
+/// gets an equal share of the timeout, so a broken path to one address family
+/// doesn't use it up before the others are tried.
+async fn connect(addresses: &[IpAddr], port: u16, limit: Duration) -> Result<TcpStream, String> {
+    let attempt_timeout = limit / addresses.len().max(1) as u32;
+    let mut last_err = "no addresses found".to_string();
+
+    for &addr in addresses {
+        let addr = SocketAddr::new(addr, port);
+
+        match timeout(attempt_timeout, TcpStream::connect(addr)).await {
+            Ok(Ok(stream)) => return Ok(stream),
+            Ok(Err(err)) => last_err = format!("failed to connect to {addr}: {err}"),
+            Err(_) => last_err = format!("connection to {addr} timed out"),
+        }
+    }
+
+    Err(last_err)
+}
+
+/// Performs the TLS handshake for HTTPS URLs and exchanges the request over
+/// the connection. The returned timings have no connect time.
+async fn exchange(
+    stream: TcpStream,
+    request: &Request<'_>,
+) -> Result<(Response, Timings), Failure> {
+    let url = request.url;
+
+    if url.scheme() != "https" {
+        let (response, first_byte) = exchange_over(stream, request).await?;
+
+        return Ok((
+            response,
+            Timings {
+                first_byte,
+                ..Timings::default()
+            },
+        ));
+    }
+
+    let host = url.host_str().unwrap_or_default().trim_matches(['[', ']']);
+
+    let start = Instant::now();
+    let stream = tls::handshake(stream, host).await?;
+    let tls = start.elapsed();
+
+    let (response, first_byte) = exchange_over(stream, request).await?;
+
+    Ok((
+        response,
+        Timings {
+            tls,
+            first_byte,
+            ..Timings::default()
+        },
+    ))
+}
+
+/// Writes the request and reads the response, returning it together with the
+/// time until its first byte arrived.
+async fn exchange_over<S>(
+    mut stream: S,
+    request: &Request<'_>,
+) -> Result<(Response, Duration), Failure>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let url = request.url;
+
+    let mut target = url.path().to_string();
+    if let Some(query) = url.query() {
+        target.push('?');
+        target.push_str(query);
+    }
+
+    let mut host = url.host_str().unwrap_or_default().to_string();
+    if let Some(port) = url.port() {
+        host.push_str(&format!(":{port}"));
+    }
+
+    let mut head = format!(
+        "{} {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: {}\r\nConnection: close\r\n",
+        request.method, target, host, USER_AGENT
+    );
+
+    if !request.body.is_empty() {
+        head.push_str(&format!("Content-Length: {}\r\n", request.body.len()));
+    }
+
+    for (name, value) in request.headers {
+        head.push_str(&format!("{name}: {value}\r\n"));
+    }
+
+    head.push_str("\r\n");
+
+    let io_failure = |err: std::io::Error| Failure::new("io", err);
+
+    stream
+        .write_all(head.as_bytes())
+        .await
+        .map_err(io_failure)?;
+    stream.write_all(request.body).await.map_err(io_failure)?;
+    stream.flush().await.map_err(io_failure)?;
+
+    let start = Instant::now();
+    let mut first_byte = None;
+    let mut raw = Vec::new();
+    let mut buf = vec![0; 8192];
+
+    // servers ignoring Connection: close keep the connection open, so the
+    // response ends where its head says
+    while raw.len() < MAX_BODY_SIZE && !is_complete(&raw) {
+        let limit = buf.len().min(MAX_BODY_SIZE - raw.len());
+        let read = stream.read(&mut buf[..limit]).await.map_err(io_failure)?;
+        first_byte.get_or_insert_with(|| start.elapsed());
+
+        if read == 0 {
+            break;
+        }
+
+        raw.extend_from_slice(&buf[..read]);
+    }
+
+    let first_byte = first_byte.unwrap_or_default();
+    let response = parse_response(&raw).map_err(|err| Failure::new("http", err))?;
+
+    Ok((response, first_byte))
+}
+
+/// Where the body of a response ends.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Framing {
+    Length(usize),
+    Chunked,
+    /// Neither, the body ends with the connection.
+    Close,
+}
+
+struct Head {
+    status: u16,
+    framing: Framing,
+}
+
+fn parse_head(head: &[u8]) -> Result<Head, String> {
+    let head = String::from_utf8_lossy(head);
+    let mut lines = head.split("\r\n");
+
+    let status = lines
+        .next()
+        .and_then(|line| line.split(' ').nth(1))
+        .and_then(|status| status.parse::<u16>().ok())
+        .ok_or("invalid status line")?;
+
+    let mut chunked = false;
+    let mut length = None;
+
+    for (name, value) in lines.filter_map(|line| line.split_once(':')) {
+        let value = value.trim();
+
+        if name.eq_ignore_ascii_case("transfer-encoding") {
+            chunked |= value.eq_ignore_ascii_case("chunked");
+        } else if name.eq_ignore_ascii_case("content-length") {
+            length = Some(
+                value
+                    .parse::<usize>()
+                    .map_err(|_| "invalid content length")?,
+            );
+        }
+    }
+
+    // chunked encoding overrides the length
+    let framing = match (status, chunked, length) {
+        (204 | 304, ..) => Framing::Length(0),
+        (_, true, _) => Framing::Chunked,
+        (_, false, Some(length)) => Framing::Length(length),
+        (_, false, None) => Framing::Close,
+    };
+
+    Ok(Head { status, framing })
+}
+
+/// Whether the response received so far is complete according to its head.
+/// An invalid head is complete as well, parsing it reports the error.
+fn is_complete(raw: &[u8]) -> bool {
+    let Some(head_end) = find(raw, b"\r\n\r\n") else {
+        return false;
+    };
+    let Ok(head) = parse_head(&raw[..head_end]) else {
+        return true;
+    };
+    let body = &raw[head_end + 4..];
+
+    match head.framing {
+        Framing::Length(length) => body.len() >= length,
+        Framing::Chunked => is_last_chunk_received(body),
+        Framing::Close => false,
+    }
+}
+
+fn parse_response(raw: &[u8]) -> Result<Response, String> {
+    let head_end = find(raw, b"\r\n\r\n").ok_or("incomplete response")?;
+    let head = parse_head(&raw[..head_end])?;
+
+    let body = &raw[head_end + 4..];
+    let body = match head.framing {
+        Framing::Length(length) => body[..length.min(body.len())].to_vec(),
+        Framing::Chunked => decode_chunked(body)?,
+        Framing::Close => body.to_vec(),
+    };
+
+    Ok(Response {
+        status: head.status,
+        body,
+    })
+}
+
+fn decode_chunked(mut raw: &[u8]) -> Result<Vec<u8>, String> {
+    let mut body = Vec::new();
+
+    loop {
+        let line_end = find(raw, b"\r\n").ok_or("incomplete chunk")?;
+        let size = chunk_size(&raw[..line_end])?;
+
+        raw = &raw[line_end + 2..];
+
+        if size == 0 {
+            return Ok(body);
+        }
+
+        // a truncated response still yields the part that was received
+        let chunk = &raw[..size.min(raw.len())];
+        body.extend_from_slice(chunk);
+
+        if raw.len() < size.saturating_add(2) {
+            return Ok(body);
+        }
+
+        raw = &raw[size + 2..];
+    }
+}
+
+/// Whether the chunked body ends with the last chunk and its trailer section.
+fn is_last_chunk_received(mut raw: &[u8]) -> bool {
+    loop {
+        let Some(line_end) = find(raw, b"\r\n") else {
+            return false;
+        };
+        let Ok(size) = chunk_size(&raw[..line_end]) else {
+            return true;
+        };
+
+        raw = &raw[line_end + 2..];
+
+        if size == 0 {
+            return raw.starts_with(b"\r\n") || find(raw, b"\r\n\r\n").is_some();
+        }
+
+        if raw.len() < size.saturating_add(2) {
+            return false;
+        }
+
+        raw = &raw[size + 2..];
+    }
+}
+
+fn chunk_size(line: &[u8]) -> Result<usize, String> {
+    let line = String::from_utf8_lossy(line);
+    let size = line.split(';').next().unwrap_or_default().trim();
+
+    usize::from_str_radix(size, 16).map_err(|_| "invalid chunk size".to_string())
+}
+
+fn find(raw: &[u8], needle: &[u8]) -> Option<usize> {
+    raw.windows(needle.len())
+        .position(|window| window == needle)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_response_reads_status_and_body() {
+        let response = parse_response(
+            b"HTTP/1.1 203 Non-Authoritative Information\r\nServer: test\r\n\r\nbody",
+        )
+        .unwrap();
+
+        assert_eq!(response.status, 203);
+        assert_eq!(response.body, b"body");
+    }
+
+    #[test]
+    fn parse_response_decodes_chunked_body() {
+        let response = parse_response(
+            b"HTTP/1.1 200 OK\r\ntransfer-encoding: Chunked\r\n\r\n4\r\nWiki\r\n5;ext=1\r\npedia\r\n0\r\n\r\n",
+        )
+        .unwrap();
+
+        assert_eq!(response.status, 200);
+        assert_eq!(response.body, b"Wikipedia");
+    }
+
+    #[test]
+    fn parse_response_honours_content_length() {
+        let response =
+            parse_response(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nokHTTP/1.1").unwrap();
+        assert_eq!(response.body, b"ok");
+
+        assert!(parse_response(b"HTTP/1.1 200 OK\r\nContent-Length: x\r\n\r\n").is_err());
+    }
+
+    #[test]
+    fn responses_end_where_their_head_says() {
+        assert!(!is_complete(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n"));
+        assert!(!is_complete(
+            b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\no"
+        ));
+        assert!(is_complete(
+            b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok"
+        ));
+        assert!(is_complete(b"HTTP/1.1 204 No Content\r\n\r\n"));
+
+        let chunked = b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\nContent-Length: 1\r\n\r\n";
+        assert!(!is_complete(&[&chunked[..], b"4\r\nWiki\r\n"].concat()));
+        assert!(!is_complete(
+            &[&chunked[..], b"4\r\nWiki\r\n0\r\n"].concat()
+        ));
+        assert!(is_complete(
+            &[&chunked[..], b"4\r\nWiki\r\n0\r\n\r\n"].concat()
+        ));
+        assert!(is_complete(
+            &[&chunked[..], b"0\r\nExpires: 0\r\n\r\n"].concat()
+        ));
+
+        // without either the response ends with the connection
+        assert!(!is_complete(b"HTTP/1.1 200 OK\r\n\r\nbody"));
+    }
+
+    #[tokio::test]
+    async fn exchange_does_not_wait_for_the_connection_to_close() {
+        let (client, mut server) = tokio::io::duplex(1024);
+        let url = "http://example.com/".parse().unwrap();
+        let request = Request {
+            method: "GET",
+            url: &url,
+            headers: &[],
+            body: &[],
+            timeout: Duration::from_secs(1),
+        };
+
+        server
+            .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok")
+            .await
+            .unwrap();
+
+        let (response, _) = exchange_over(client, &request).await.unwrap();
+        assert_eq!(response.body, b"ok");
+        drop(server);
+    }
+
+    #[test]
+    fn parse_response_rejects_incomplete_head() {
+        assert!(parse_response(b"HTTP/1.1 200 OK\r\nServer: test\r\n").is_err());
+    }
+
+    #[test]
+    fn parse_response_rejects_invalid_status() {
+        assert!(parse_response(b"HTTP/1.1 OK\r\n\r\n").is_err());
+        assert!(parse_response(b"garbage\r\n\r\n").is_err());
+    }
+
+    #[test]
+    fn decode_chunked_keeps_truncated_chunk() {
+        assert_eq!(decode_chunked(b"4\r\nWiki\r\n5\r\npe").unwrap(), b"Wikipe");
+        assert_eq!(decode_chunked(b"4\r\nWiki").unwrap(), b"Wiki");
+    }
+
+    #[test]
+    fn decode_chunked_rejects_invalid_size() {
+        assert!(decode_chunked(b"zz\r\ndata\r\n0\r\n\r\n").is_err());
+    }
+
+    #[test]
+    fn decode_chunked_rejects_missing_size_line() {
+        assert!(decode_chunked(b"4\r\nWiki\r\n5").is_err());
+        assert!(decode_chunked(b"").is_err());
+    }
+
+    #[test]
+    fn validate_method_accepts_tokens_only() {
+        assert!(validate_method("POST").is_ok());
+        assert!(validate_method("").is_err());
+        assert!(validate_method("GET /x").is_err());
+        assert!(validate_method("POST\r\nX-Injected: 1").is_err());
+    }
+
+    #[test]
+    fn validate_header_rejects_line_breaks() {
+        assert!(validate_header("Authorization", "Bearer a\tb").is_ok());
+        assert!(validate_header("X Header", "value").is_err());
+        assert!(validate_header("X-Header:", "value").is_err());
+        assert!(validate_header("X-Header", "value\r\nX-Injected: 1").is_err());
+    }
+}
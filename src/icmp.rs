@@ -0,0 +1,1195 @@
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque, hash_map::RandomState},
+    hash::{BuildHasher, Hasher},
+    io, mem,
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use socket2::{Domain, Protocol, Type};
+use tokio::{io::Interest, net::UdpSocket, sync::oneshot};
+use tracing::{Span, debug, info};
+
+use crate::{
+    bind,
+    config::{Binding, Socket, Target},
+    metrics::TargetKey,
+    probe::{Failure, Reply},
+    stats::Anomalies,
+    tcp,
+};
+
+const HEADER_SIZE: usize = 8;
+const IPV6_HEADER_SIZE: usize = 40;
+
+const ECHO_REQUEST_V4: u8 = 8;
+const ECHO_REPLY_V4: u8 = 0;
+const UNREACHABLE_V4: u8 = 3;
+const TIME_EXCEEDED_V4: u8 = 11;
+/// Destination unreachable code for a packet that needs fragmentation but has DF set.
+const FRAGMENTATION_NEEDED_V4: u8 = 4;
+
+const ECHO_REQUEST_V6: u8 = 128;
+const ECHO_REPLY_V6: u8 = 129;
+const UNREACHABLE_V6: u8 = 1;
+const PACKET_TOO_BIG_V6: u8 = 2;
+const TIME_EXCEEDED_V6: u8 = 3;
+
+/// Number of requests per address remembered to recognize late, duplicate and
+/// reordered replies.
+const HISTORY: usize = 1024;
+/// Time after which the sequence of an address that isn't probed anymore is
+/// forgotten.
+const FLOW_EXPIRY: Duration = Duration::from_secs(10 * 60);
+/// Time after which a socket that isn't used anymore, like one of a target
+/// removed on reload, is closed.
+const SOCKET_EXPIRY: Duration = FLOW_EXPIRY;
+/// Wait after the first of consecutive receive errors, doubled on every
+/// further one up to the maximum.
+const MIN_RECEIVE_BACKOFF: Duration = Duration::from_millis(10);
+const MAX_RECEIVE_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Sends echo requests for all targets over shared sockets and matches the
+/// replies to the outstanding requests.
+///
+/// Targets with the default settings share one socket per address family,
+/// targets with their own TTL, DF setting or binding share one per
+/// combination. Sockets no target uses anymore are closed after a while.
+#[derive(Clone, Default)]
+pub struct Engine {
+    sockets: Arc<Mutex<HashMap<Settings, OpenSocket>>>,
+}
+
+/// A socket of the engine and its receiver, which stops when this is dropped.
+struct OpenSocket {
+    socket: Arc<EchoSocket>,
+    last_used: Instant,
+    _stop: oneshot::Sender<()>,
+}
+
+/// Socket options that apply to every request sent over a socket.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+struct Settings {
+    ipv6: bool,
+    socket_type: Socket,
+    ttl: Option<u8>,
+    dont_fragment: bool,
+    binding: Binding,
+}
+
+impl Settings {
+    fn new(target: &Target, addr: IpAddr) -> Self {
+        Self {
+            ipv6: addr.is_ipv6(),
+            socket_type: target.socket_type,
+            ttl: target.ttl.map(|ttl| ttl.get()),
+            dont_fragment: target.dont_fragment,
+            binding: target.binding.clone(),
+        }
+    }
+}
+
+/// An echo reply or an ICMP error about an echo request.
+#[derive(Debug)]
+pub struct Response {
+    /// Sender of the reply, or the router reporting an error on the way.
+    pub from: IpAddr,
+    pub rtt: Duration,
+    pub seq: u16,
+    /// Why this isn't the expected reply, `None` if it is.
+    pub error: Option<Failure>,
+}
+
+impl Engine {
+    /// Sends the next echo request of the address' sequence and waits for
+    /// the reply.
+    pub async fn ping(&self, target: &Target, addr: IpAddr) -> Result<Reply, Failure> {
+        let response = self
+            .request(target, addr, Some(TargetKey::new(target)))
+            .await?;
+
+        if let Some(failure) = response.error {
+            return Err(failure);
+        }
+
+        Ok(Reply {
+            rtt: response.rtt,
+            detail: Some(format!("icmp_seq={}", response.seq)),
+            warning: None,
+            phases: Vec::new(),
+        })
+    }
+
+    /// Sends the next echo request of the address' sequence and waits for
+    /// the reply or an ICMP error about it, failing only if neither arrives.
+    /// Unlike pings, its anomalous replies aren't counted for the target.
+    pub async fn echo(&self, target: &Target, addr: IpAddr) -> Result<Response, Failure> {
+        self.request(target, addr, None).await
+    }
+
+    async fn request(
+        &self,
+        target: &Target,
+        addr: IpAddr,
+        owner: Option<TargetKey>,
+    ) -> Result<Response, Failure> {
+        let socket = self
+            .socket(Settings::new(target, addr))
+            .map_err(io_failure)?;
+        let payload = payload(target.size, target.pattern.as_ref().map(|p| p.bytes()));
+
+        socket.echo(addr, payload, owner, target.timeout).await
+    }
+
+    /// Returns the late, duplicate and reordered replies to the target's
+    /// pings of the address since the last call. Targets probing the same
+    /// address with the same settings share a sequence but count their own.
+    pub fn take_anomalies(&self, target: &Target, addr: IpAddr) -> Anomalies {
+        let socket = self
+            .sockets
+            .lock()
+            .unwrap()
+            .get(&Settings::new(target, addr))
+            .map(|open| open.socket.clone());
+
+        socket
+            .and_then(|socket| {
+                let mut flows = socket.flows.lock().unwrap();
+                flows
+                    .get_mut(&addr)
+                    .and_then(|flow| flow.anomalies.remove(&TargetKey::new(target)))
+            })
+            .unwrap_or_default()
+    }
+
+    /// Returns the socket for the settings, opening it and starting its
+    /// receiver on first use. Failures aren't cached, so a missing permission
+    /// that gets granted later is picked up. Sockets that weren't used for
+    /// [`SOCKET_EXPIRY`] are closed.
+    fn socket(&self, settings: Settings) -> io::Result<Arc<EchoSocket>> {
+        let mut sockets = self.sockets.lock().unwrap();
+        let now = Instant::now();
+
+        prune(&mut sockets, now);
+
+        if let Some(open) = sockets.get_mut(&settings) {
+            open.last_used = now;
+            return Ok(open.socket.clone());
+        }
+
+        let socket = Arc::new(EchoSocket {
+            socket: open(&settings)?,
+            settings: settings.clone(),
+            ident: random(),
+            flows: Mutex::default(),
+        });
+        let (stop, stopped) = oneshot::channel();
+
+        tokio::spawn(receive(socket.clone(), stopped));
+        sockets.insert(
+            settings,
+            OpenSocket {
+                socket: socket.clone(),
+                last_used: now,
+                _stop: stop,
+            },
+        );
+
+        Ok(socket)
+    }
+}
+
+/// Drops the sockets that weren't used for [`SOCKET_EXPIRY`] and have no
+/// request in flight, which stops their receivers and closes them.
+fn prune(sockets: &mut HashMap<Settings, OpenSocket>, now: Instant) {
+    sockets.retain(|_, open| {
+        // the map and the receiver hold the socket, requests in flight too
+        now.duration_since(open.last_used) < SOCKET_EXPIRY || Arc::strong_count(&open.socket) > 2
+    });
+}
+
+/// A shared ICMP socket and the requests sent over it.
+struct EchoSocket {
+    socket: UdpSocket,
+    settings: Settings,
+    /// Identifier of requests sent over raw sockets, datagram sockets replace
+    /// it with their own.
+    ident: u16,
+    /// Requests by destination, replies are matched by their source address
+    /// and errors by the destination of the request they quote.
+    flows: Mutex<HashMap<IpAddr, Flow>>,
+}
+
+/// Marks a request as timed out when its probe gives up waiting or is
+/// cancelled, so a reply arriving later is counted as late.
+struct PendingGuard<'a> {
+    socket: &'a EchoSocket,
+    addr: IpAddr,
+    seq: u16,
+}
+
+impl Drop for PendingGuard<'_> {
+    fn drop(&mut self) {
+        if let Some(request) = self
+            .socket
+            .flows
+            .lock()
+            .unwrap()
+            .get_mut(&self.addr)
+            .and_then(|flow| flow.request(self.seq))
+            && matches!(request.state, State::Pending { .. })
+        {
+            request.state = State::TimedOut;
+        }
+    }
+}
+
+impl EchoSocket {
+    async fn echo(
+        &self,
+        addr: IpAddr,
+        payload: Vec<u8>,
+        owner: Option<TargetKey>,
+        timeout: Duration,
+    ) -> Result<Response, Failure> {
+        let (reply, received) = oneshot::channel();
+        let start = Instant::now();
+
+        let seq = {
+            let mut flows = self.flows.lock().unwrap();
+            // forget addresses that aren't probed anymore, like old DNS answers
+            if !flows.contains_key(&addr) {
+                flows.retain(|_, flow| flow.last_sent.elapsed() < FLOW_EXPIRY);
+            }
+
+            flows.entry(addr).or_insert_with(Flow::new).push(Request {
+                sent: start,
+                span: Span::current(),
+                owner,
+                state: State::Pending {
+                    payload: payload.clone(),
+                    reply,
+                },
+            })
+        };
+        let _guard = PendingGuard {
+            socket: self,
+            addr,
+            seq,
+        };
+
+        let packet = encode(addr, self.ident, seq, &payload);
+        let dest = SocketAddr::new(addr, 0);
+        let mut sent = self.socket.send_to(&packet, dest).await;
+        // datagram sockets report a pending ICMP error about an earlier
+        // request on the next send, the error queue has it already
+        if sent.is_err() && self.settings.socket_type == Socket::Datagram {
+            sent = self.socket.send_to(&packet, dest).await;
+        }
+        sent.map_err(io_failure)?;
+
+        match tokio::time::timeout(timeout, received).await {
+            Ok(Ok(answer)) => Ok(Response {
+                from: answer.from,
+                rtt: answer.at.saturating_duration_since(start),
+                seq,
+                error: answer.error,
+            }),
+            Ok(Err(_)) => Err(Failure::new("internal", "ICMP receiver stopped")),
+            Err(_) => Err(Failure::new(
+                "timeout",
+                format!("no reply received, icmp_seq={seq}"),
+            )),
+        }
+    }
+
+    /// Hands a reply or error to the flow of the request it is about.
+    fn deliver(&self, from: IpAddr, message: Message<'_>, at: Instant) {
+        if let Some(flow) = self.flows.lock().unwrap().get_mut(&message.addr) {
+            flow.receive(from, message.seq, message.kind, at);
+        }
+    }
+}
+
+/// The requests sent to one address, numbered consecutively.
+struct Flow {
+    /// Sequence number of the first remembered request.
+    first_seq: u16,
+    requests: VecDeque<Request>,
+    /// Highest sequence number answered so far.
+    highest: Option<u16>,
+    last_sent: Instant,
+    /// Anomalous replies by the target whose requests they answered.
+    anomalies: BTreeMap<TargetKey, Anomalies>,
+}
+
+struct Request {
+    sent: Instant,
+    /// Span of the probe, so replies are logged as part of their target.
+    span: Span,
+    /// Target counting the anomalous replies, `None` for the requests of
+    /// route traces and path MTU discovery.
+    owner: Option<TargetKey>,
+    state: State,
+}
+
+enum State {
+    Pending {
+        payload: Vec<u8>,
+        reply: oneshot::Sender<Answer>,
+    },
+    TimedOut,
+    Answered,
+}
+
+/// The reply or error received for a pending request.
+struct Answer {
+    at: Instant,
+    from: IpAddr,
+    error: Option<Failure>,
+}
+
+impl Flow {
+    fn new() -> Self {
+        Self {
+            first_seq: 0,
+            requests: VecDeque::new(),
+            highest: None,
+            last_sent: Instant::now(),
+            anomalies: BTreeMap::new(),
+        }
+    }
+
+    /// Remembers the request and returns its sequence number.
+    fn push(&mut self, request: Request) -> u16 {
+        if self.requests.len() == HISTORY {
+            self.requests.pop_front();
+            self.first_seq = self.first_seq.wrapping_add(1);
+        }
+
+        self.last_sent = request.sent;
+        self.requests.push_back(request);
+
+        self.first_seq
+            .wrapping_add((self.requests.len() - 1) as u16)
+    }
+
+    fn request(&mut self, seq: u16) -> Option<&mut Request> {
+        self.requests
+            .get_mut(usize::from(seq.wrapping_sub(self.first_seq)))
+    }
+
+    /// Hands a reply or error to its request, counting replies to requests
+    /// that timed out or were answered already.
+    fn receive(&mut self, from: IpAddr, seq: u16, kind: Kind, at: Instant) {
+        let highest = self.highest;
+        let Some(request) = self.request(seq) else {
+            return;
+        };
+
+        let payload = match kind {
+            Kind::Reply(payload) => payload,
+            Kind::Error(failure) => {
+                // errors about requests that timed out aren't interesting anymore
+                if let State::Pending { reply, .. } =
+                    mem::replace(&mut request.state, State::Answered)
+                {
+                    let _ = reply.send(Answer {
+                        at,
+                        from,
+                        error: Some(failure),
+                    });
+                }
+
+                return;
+            }
+        };
+
+        let _span = request.span.clone().entered();
+        let rtt = as_millis(at.saturating_duration_since(request.sent));
+        let owner = request.owner.clone();
+        let state = mem::replace(&mut request.state, State::Answered);
+
+        let mut uncounted = Anomalies::default();
+        let anomalies = match owner {
+            Some(owner) => self.anomalies.entry(owner).or_default(),
+            None => &mut uncounted,
+        };
+
+        match state {
+            State::Pending {
+                payload: sent,
+                reply,
+            } => {
+                let error = (payload != sent)
+                    .then(|| Failure::new("payload", "reply payload differs from the request"));
+
+                // the probe may have timed out in the meantime
+                let _ = reply.send(Answer { at, from, error });
+
+                if let Some(highest) = highest
+                    && is_before(seq, highest)
+                {
+                    anomalies.reordered += 1;
+                    info!(
+                        "Reordered reply from {}, icmp_seq={} after icmp_seq={}",
+                        from, seq, highest
+                    );
+                }
+            }
+            State::TimedOut => {
+                anomalies.late += 1;
+                info!(
+                    "Late reply from {}, icmp_seq={}, time={:.3} ms",
+                    from, seq, rtt
+                );
+            }
+            State::Answered => {
+                anomalies.duplicates += 1;
+                info!(
+                    "Duplicate reply from {}, icmp_seq={}, time={:.3} ms",
+                    from, seq, rtt
+                );
+
+                return;
+            }
+        }
+
+        if highest.is_none_or(|highest| is_before(highest, seq)) {
+            self.highest = Some(seq);
+        }
+    }
+}
+
+/// Compares sequence numbers that may have wrapped around.
+fn is_before(seq: u16, other: u16) -> bool {
+    (seq.wrapping_sub(other) as i16) < 0
+}
+
+fn as_millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+/// Receives the packets arriving on the socket and hands each one to the
+/// request it belongs to. Runs until the engine drops the socket.
+async fn receive(socket: Arc<EchoSocket>, mut stop: oneshot::Receiver<()>) {
+    let Settings {
+        ipv6, socket_type, ..
+    } = socket.settings;
+    // the kernel only strips the IPv4 header from datagram sockets
+    let has_ip_header = !ipv6 && socket_type == Socket::Raw;
+    // and matches the identifier of their replies itself
+    let ident = (socket_type == Socket::Raw).then_some(socket.ident);
+    // datagram sockets keep ICMP errors apart from the replies
+    let error_queue = socket_type == Socket::Datagram && cfg!(target_os = "linux");
+
+    let mut buf = vec![0; usize::from(u16::MAX)];
+    let mut errors = 0;
+
+    loop {
+        let (len, from) = tokio::select! {
+            _ = &mut stop => return,
+            received = socket.socket.recv_from(&mut buf) => match received {
+                Ok(received) => {
+                    errors = 0;
+                    received
+                }
+                Err(err) => {
+                    debug!("Failed to receive ICMP packet: {}", err);
+
+                    // datagram sockets return an ICMP error once, a socket
+                    // failing over and over would spin
+                    errors += 1;
+                    if errors > 1 {
+                        tokio::time::sleep(receive_backoff(errors - 1)).await;
+                    }
+                    continue;
+                }
+            },
+            queued = socket.socket.async_io(Interest::ERROR, || recv_error(&socket.socket)),
+                if error_queue =>
+            {
+                errors = 0;
+
+                match queued {
+                    Ok(Some((from, message))) => socket.deliver(from, message, Instant::now()),
+                    Ok(None) => {}
+                    Err(err) => debug!("Failed to receive ICMP error: {}", err),
+                }
+                continue;
+            }
+        };
+
+        let at = Instant::now();
+        let mut packet = &buf[..len];
+
+        if has_ip_header {
+            let header_len = usize::from(packet.first().copied().unwrap_or_default() & 0x0f) * 4;
+            packet = packet.get(header_len..).unwrap_or_default();
+        }
+
+        let Some(message) = parse(packet, from.ip(), ipv6) else {
+            continue;
+        };
+
+        if ident.is_some_and(|ident| ident != message.ident) {
+            continue;
+        }
+
+        socket.deliver(from.ip(), message, at);
+    }
+}
+
+/// Time to wait after the given number of repeated receive errors.
+fn receive_backoff(repeated: u32) -> Duration {
+    MIN_RECEIVE_BACKOFF
+        .saturating_mul(2u32.saturating_pow(repeated - 1))
+        .min(MAX_RECEIVE_BACKOFF)
+}
+
+/// Opens a non-blocking ICMP socket with the settings applied. Tokio's UDP
+/// socket is used for its async `send_to` and `recv_from`, which work for any
+/// datagram oriented socket.
+fn open(settings: &Settings) -> io::Result<UdpSocket> {
+    let ty = match settings.socket_type {
+        Socket::Raw => Type::RAW,
+        Socket::Datagram => Type::DGRAM,
+    };
+
+    let socket = if settings.ipv6 {
+        let socket = socket2::Socket::new(Domain::IPV6, ty, Some(Protocol::ICMPV6))?;
+        if let Some(ttl) = settings.ttl {
+            socket.set_unicast_hops_v6(u32::from(ttl))?;
+        }
+        socket
+    } else {
+        let socket = socket2::Socket::new(Domain::IPV4, ty, Some(Protocol::ICMPV4))?;
+        if let Some(ttl) = settings.ttl {
+            socket.set_ttl_v4(u32::from(ttl))?;
+        }
+        socket
+    };
+
+    set_dont_fragment(&socket, settings.ipv6, settings.dont_fragment)?;
+    if settings.socket_type == Socket::Datagram {
+        set_recv_errors(&socket, settings.ipv6)?;
+    }
+    bind::apply(&socket, &settings.binding)?;
+    socket.set_nonblocking(true)?;
+
+    UdpSocket::from_std(socket.into())
+}
+
+/// Builds an echo request with the identifier and sequence number.
+fn encode(addr: IpAddr, ident: u16, seq: u16, payload: &[u8]) -> Vec<u8> {
+    let kind = if addr.is_ipv4() {
+        ECHO_REQUEST_V4
+    } else {
+        ECHO_REQUEST_V6
+    };
+
+    let mut packet = vec![kind, 0, 0, 0];
+    packet.extend_from_slice(&ident.to_be_bytes());
+    packet.extend_from_slice(&seq.to_be_bytes());
+    packet.extend_from_slice(payload);
+
+    // the kernel computes ICMPv6 checksums as they cover the IPv6 header
+    if addr.is_ipv4() {
+        let checksum = checksum(&packet);
+        packet[2..4].copy_from_slice(&checksum.to_be_bytes());
+    }
+
+    packet
+}
+
+/// An ICMP packet about one of our echo requests.
+#[derive(Debug)]
+struct Message<'a> {
+    /// Destination of the request, the source of a reply.
+    addr: IpAddr,
+    ident: u16,
+    seq: u16,
+    kind: Kind<'a>,
+}
+
+#[derive(Debug)]
+enum Kind<'a> {
+    /// An echo reply with its payload.
+    Reply(&'a [u8]),
+    /// An error quoting the request.
+    Error(Failure),
+}
+
+/// Parses a packet without IP header into an echo reply or an ICMP error
+/// about an echo request. Anything else is `None`.
+fn parse(packet: &[u8], from: IpAddr, ipv6: bool) -> Option<Message<'_>> {
+    let header = packet.get(..HEADER_SIZE)?;
+    let (kind, code) = (header[0], header[1]);
+
+    if (!ipv6 && kind == ECHO_REPLY_V4) || (ipv6 && kind == ECHO_REPLY_V6) {
+        let (ident, seq) = ident_and_seq(header);
+
+        return Some(Message {
+            addr: from,
+            ident,
+            seq,
+            kind: Kind::Reply(&packet[HEADER_SIZE..]),
+        });
+    }
+
+    // errors quote the IP header and at least 8 bytes of the request
+    let quoted = &packet[HEADER_SIZE..];
+    let (addr, request) = if ipv6 {
+        let addr = <[u8; 16]>::try_from(quoted.get(24..40)?).ok()?;
+        (IpAddr::from(addr), quoted.get(IPV6_HEADER_SIZE..)?)
+    } else {
+        let header_len = usize::from(quoted.first().copied().unwrap_or_default() & 0x0f) * 4;
+        let addr = <[u8; 4]>::try_from(quoted.get(16..20)?).ok()?;
+        (IpAddr::from(addr), quoted.get(header_len..)?)
+    };
+    let request = request.get(..HEADER_SIZE)?;
+
+    let request_kind = if ipv6 {
+        ECHO_REQUEST_V6
+    } else {
+        ECHO_REQUEST_V4
+    };
+
+    if request[0] != request_kind {
+        return None;
+    }
+
+    let mtu = if ipv6 {
+        u32::from_be_bytes([header[4], header[5], header[6], header[7]])
+    } else {
+        u32::from(u16::from_be_bytes([header[6], header[7]]))
+    };
+    let failure = failure(ipv6, kind, code, mtu, from)?;
+
+    let (ident, seq) = ident_and_seq(request);
+
+    Some(Message {
+        addr,
+        ident,
+        seq,
+        kind: Kind::Error(failure),
+    })
+}
+
+/// Describes an ICMP error from `from`, with the MTU it reports if it is
+/// about the packet size. Anything but an error about the request is `None`.
+fn failure(ipv6: bool, kind: u8, code: u8, mtu: u32, from: IpAddr) -> Option<Failure> {
+    let failure = match (ipv6, kind, code) {
+        (false, UNREACHABLE_V4, FRAGMENTATION_NEEDED_V4) => Failure::new(
+            "mtu",
+            format!("fragmentation needed at {from}, next-hop MTU {mtu}"),
+        ),
+        (true, PACKET_TOO_BIG_V6, _) => {
+            Failure::new("mtu", format!("packet too big at {from}, MTU {mtu}"))
+        }
+        (false, UNREACHABLE_V4, code) | (true, UNREACHABLE_V6, code) => Failure::new(
+            "unreachable",
+            format!("destination unreachable from {from}, code {code}"),
+        ),
+        (false, TIME_EXCEEDED_V4, _) | (true, TIME_EXCEEDED_V6, _) => {
+            Failure::new("ttl", format!("time to live exceeded at {from}"))
+        }
+        _ => return None,
+    };
+
+    Some(failure)
+}
+
+fn ident_and_seq(header: &[u8]) -> (u16, u16) {
+    (
+        u16::from_be_bytes([header[4], header[5]]),
+        u16::from_be_bytes([header[6], header[7]]),
+    )
+}
+
+/// Sets or clears the DF bit, or forbids fragmentation for IPv6, instead of
+/// leaving it to the kernel's path MTU discovery default.
+#[cfg(target_os = "linux")]
+fn set_dont_fragment(socket: &socket2::Socket, ipv6: bool, enabled: bool) -> io::Result<()> {
+    let (level, name, value) = match (ipv6, enabled) {
+        (false, true) => (
+            libc::IPPROTO_IP,
+            libc::IP_MTU_DISCOVER,
+            libc::IP_PMTUDISC_DO,
+        ),
+        (false, false) => (
+            libc::IPPROTO_IP,
+            libc::IP_MTU_DISCOVER,
+            libc::IP_PMTUDISC_DONT,
+        ),
+        (true, enabled) => (libc::IPPROTO_IPV6, libc::IPV6_DONTFRAG, enabled.into()),
+    };
+
+    set_option(socket, level, name, value)
+}
+
+/// Has the kernel queue the ICMP errors about requests sent over a datagram
+/// socket, it drops them otherwise as they carry no data.
+#[cfg(target_os = "linux")]
+fn set_recv_errors(socket: &socket2::Socket, ipv6: bool) -> io::Result<()> {
+    if ipv6 {
+        set_option(socket, libc::IPPROTO_IPV6, libc::IPV6_RECVERR, 1)
+    } else {
+        set_option(socket, libc::IPPROTO_IP, libc::IP_RECVERR, 1)
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn set_option(
+    socket: &socket2::Socket,
+    level: libc::c_int,
+    name: libc::c_int,
+    value: libc::c_int,
+) -> io::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    // SAFETY: the option value is a valid c_int that outlives the call
+    let res = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            level,
+            name,
+            (&value as *const libc::c_int).cast(),
+            size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+
+    if res == -1 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+/// Reads the next error off the error queue of a datagram socket. Returns the
+/// router that reported it and the error about the request, or `None` for
+/// errors that aren't ICMP errors, like the local ones of oversized sends.
+#[cfg(target_os = "linux")]
+fn recv_error(socket: &UdpSocket) -> io::Result<Option<(IpAddr, Message<'static>)>> {
+    use std::os::fd::AsRawFd;
+
+    // the queued packet is the request, starting with its ICMP header
+    let mut request = [0u8; HEADER_SIZE];
+    let mut iov = libc::iovec {
+        iov_base: request.as_mut_ptr().cast(),
+        iov_len: request.len(),
+    };
+    // SAFETY: all zeroes are a valid socket address and message header
+    let mut name: libc::sockaddr_storage = unsafe { mem::zeroed() };
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    // aligned for the control message headers
+    let mut control = [0u64; 64];
+
+    msg.msg_name = (&raw mut name).cast();
+    msg.msg_namelen = size_of_val(&name) as libc::socklen_t;
+    msg.msg_iov = &raw mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr().cast();
+    msg.msg_controllen = size_of_val(&control);
+
+    // SAFETY: the buffers of the message header outlive the call
+    let len = unsafe {
+        libc::recvmsg(
+            socket.as_raw_fd(),
+            &mut msg,
+            libc::MSG_ERRQUEUE | libc::MSG_DONTWAIT,
+        )
+    };
+
+    if len == -1 {
+        return Err(io::Error::last_os_error());
+    }
+
+    // the address the kernel returns is the destination of the request
+    // SAFETY: the kernel wrote a socket address of its family
+    let Some(addr) = (unsafe { sockaddr_ip((&raw const name).cast()) }) else {
+        return Ok(None);
+    };
+
+    if (len as usize) < HEADER_SIZE {
+        return Ok(None);
+    }
+
+    // SAFETY: the kernel wrote msg_controllen bytes of control messages,
+    // an extended error is followed by the address of the reporting router
+    unsafe {
+        let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+
+        while !cmsg.is_null() {
+            let (level, ty) = ((*cmsg).cmsg_level, (*cmsg).cmsg_type);
+
+            if (level, ty) == (libc::IPPROTO_IP, libc::IP_RECVERR)
+                || (level, ty) == (libc::IPPROTO_IPV6, libc::IPV6_RECVERR)
+            {
+                let data = libc::CMSG_DATA(cmsg);
+                let err = data.cast::<libc::sock_extended_err>().read_unaligned();
+
+                let ipv6 = match err.ee_origin {
+                    libc::SO_EE_ORIGIN_ICMP => false,
+                    libc::SO_EE_ORIGIN_ICMP6 => true,
+                    _ => return Ok(None),
+                };
+                let from = sockaddr_ip(data.add(size_of::<libc::sock_extended_err>()).cast())
+                    .unwrap_or(addr);
+                let Some(failure) = failure(ipv6, err.ee_type, err.ee_code, err.ee_info, from)
+                else {
+                    return Ok(None);
+                };
+                let (ident, seq) = ident_and_seq(&request);
+
+                return Ok(Some((
+                    from,
+                    Message {
+                        addr,
+                        ident,
+                        seq,
+                        kind: Kind::Error(failure),
+                    },
+                )));
+            }
+
+            cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+        }
+    }
+
+    Ok(None)
+}
+
+/// Reads the IP address of an IPv4 or IPv6 socket address, `None` for other
+/// families.
+///
+/// # Safety
+///
+/// `addr` must point to a socket address as large as its family's.
+#[cfg(target_os = "linux")]
+unsafe fn sockaddr_ip(addr: *const libc::sockaddr) -> Option<IpAddr> {
+    // SAFETY: guaranteed by the caller
+    unsafe {
+        match libc::c_int::from(addr.cast::<libc::sa_family_t>().read_unaligned()) {
+            libc::AF_INET => {
+                let addr = addr.cast::<libc::sockaddr_in>().read_unaligned();
+                Some(IpAddr::from(addr.sin_addr.s_addr.to_ne_bytes()))
+            }
+            libc::AF_INET6 => {
+                let addr = addr.cast::<libc::sockaddr_in6>().read_unaligned();
+                Some(IpAddr::from(addr.sin6_addr.s6_addr))
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_dont_fragment(_socket: &socket2::Socket, _ipv6: bool, enabled: bool) -> io::Result<()> {
+    if enabled {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "don't fragment is only supported on Linux",
+        ));
+    }
+
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_recv_errors(_socket: &socket2::Socket, _ipv6: bool) -> io::Result<()> {
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn recv_error(_socket: &UdpSocket) -> io::Result<Option<(IpAddr, Message<'static>)>> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+/// Builds a payload of `size` bytes repeating the pattern, or counting up
+/// like `ping` without one.
+pub fn payload(size: usize, pattern: Option<&[u8]>) -> Vec<u8> {
+    match pattern {
+        Some(pattern) => pattern.iter().copied().cycle().take(size).collect(),
+        None => (0..size).map(|idx| idx as u8).collect(),
+    }
+}
+
+/// Internet checksum as defined by RFC 1071.
+fn checksum(data: &[u8]) -> u16 {
+    let mut sum = data
+        .chunks(2)
+        .map(|chunk| u32::from(u16::from_be_bytes([chunk[0], *chunk.get(1).unwrap_or(&0)])))
+        .sum::<u32>();
+
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+
+    !(sum as u16)
+}
+
+fn random() -> u16 {
+    RandomState::new().build_hasher().finish() as u16
+}
+
+/// Classifies a socket error for the failure metrics.
+fn error_kind(err: &io::Error) -> &'static str {
+    #[cfg(unix)]
+    if err.raw_os_error() == Some(libc::EMSGSIZE) {
+        return "mtu";
+    }
+
+    tcp::error_kind(err)
+}
+
+fn io_failure(err: io::Error) -> Failure {
+    Failure::new(error_kind(&err), err)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    const ADDR: IpAddr = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+    const ROUTER: IpAddr = IpAddr::V4(Ipv4Addr::new(198, 51, 100, 1));
+
+    fn request() -> Vec<u8> {
+        encode(ADDR, 0x1234, 7, &payload(16, Some(&[0xab, 0xcd])))
+    }
+
+    /// Builds an ICMP error quoting the request behind a minimal IPv4 header.
+    fn error(kind: u8, code: u8, rest: [u8; 4], request: &[u8]) -> Vec<u8> {
+        let mut packet = vec![kind, code, 0, 0];
+        packet.extend_from_slice(&rest);
+        packet.push(0x45);
+        packet.extend_from_slice(&[0; 15]);
+        packet.extend_from_slice(&[192, 0, 2, 1]);
+        packet.extend_from_slice(&request[..HEADER_SIZE]);
+        packet
+    }
+
+    #[test]
+    fn payload_repeats_the_pattern() {
+        assert_eq!(payload(5, Some(&[1, 2])), [1, 2, 1, 2, 1]);
+        assert_eq!(payload(3, None), [0, 1, 2]);
+        assert!(payload(0, Some(&[1])).is_empty());
+    }
+
+    #[test]
+    fn checksum_of_encoded_request_verifies() {
+        let mut packet = request();
+        assert_eq!(checksum(&packet), 0);
+
+        // odd lengths are padded with a zero byte
+        packet.push(0x01);
+        packet[2..4].copy_from_slice(&[0, 0]);
+        let sum = checksum(&packet);
+        packet[2..4].copy_from_slice(&sum.to_be_bytes());
+        assert_eq!(checksum(&packet), 0);
+    }
+
+    #[test]
+    fn replies_carry_identifier_and_payload() {
+        let mut packet = request();
+        packet[0] = ECHO_REPLY_V4;
+
+        let message = parse(&packet, ADDR, false).unwrap();
+        assert_eq!((message.ident, message.seq), (0x1234, 7));
+        assert!(matches!(message.kind, Kind::Reply(payload) if payload == &packet[HEADER_SIZE..]));
+
+        // requests, like our own looped back ones, aren't replies
+        assert!(parse(&request(), ADDR, false).is_none());
+        assert!(parse(&packet, ADDR, true).is_none());
+    }
+
+    #[test]
+    fn errors_identify_the_request() {
+        let packet = error(
+            UNREACHABLE_V4,
+            FRAGMENTATION_NEEDED_V4,
+            [0, 0, 5, 0xdc],
+            &request(),
+        );
+        let message = parse(&packet, ROUTER, false).unwrap();
+        assert_eq!(
+            (message.addr, message.ident, message.seq),
+            (ADDR, 0x1234, 7)
+        );
+        match message.kind {
+            Kind::Error(failure) => {
+                assert_eq!(failure.kind, "mtu");
+                assert_eq!(
+                    failure.message,
+                    "fragmentation needed at 198.51.100.1, next-hop MTU 1500"
+                );
+            }
+            kind => panic!("unexpected {kind:?}"),
+        }
+
+        let packet = error(TIME_EXCEEDED_V4, 0, [0; 4], &request());
+        match parse(&packet, ROUTER, false).unwrap().kind {
+            Kind::Error(failure) => assert_eq!(failure.kind, "ttl"),
+            kind => panic!("unexpected {kind:?}"),
+        }
+
+        // errors about other packets than echo requests
+        let mut other = request();
+        other[0] = ECHO_REPLY_V4;
+        assert!(parse(&error(UNREACHABLE_V4, 1, [0; 4], &other), ROUTER, false).is_none());
+    }
+
+    fn owner(label: &str) -> Option<TargetKey> {
+        Some(TargetKey::from_parts(
+            &"192.0.2.1".parse().unwrap(),
+            Some(label),
+        ))
+    }
+
+    fn pending(flow: &mut Flow, owner: Option<TargetKey>) -> (u16, oneshot::Receiver<Answer>) {
+        let (reply, received) = oneshot::channel();
+        let seq = flow.push(Request {
+            sent: Instant::now(),
+            span: Span::none(),
+            owner,
+            state: State::Pending {
+                payload: vec![1, 2],
+                reply,
+            },
+        });
+
+        (seq, received)
+    }
+
+    #[test]
+    fn replies_out_of_sequence_are_counted() {
+        let mut flow = Flow::new();
+        let (first, mut first_reply) = pending(&mut flow, owner("a"));
+        let (second, mut second_reply) = pending(&mut flow, owner("a"));
+        let (third, _) = pending(&mut flow, owner("a"));
+        let (other, _) = pending(&mut flow, owner("b"));
+        let (trace, _) = pending(&mut flow, None);
+        assert_eq!((first, second, third), (0, 1, 2));
+
+        let now = Instant::now();
+        flow.receive(ADDR, second, Kind::Reply(&[1, 2]), now);
+        assert!(second_reply.try_recv().unwrap().error.is_none());
+
+        flow.receive(ADDR, first, Kind::Reply(&[1, 2]), now);
+        assert!(first_reply.try_recv().unwrap().error.is_none());
+        flow.receive(ADDR, first, Kind::Reply(&[1, 2]), now);
+
+        flow.request(third).unwrap().state = State::TimedOut;
+        flow.receive(ADDR, third, Kind::Reply(&[1, 2]), now);
+
+        // other targets sharing the sequence count their own, traces none
+        for seq in [other, trace] {
+            flow.request(seq).unwrap().state = State::TimedOut;
+            flow.receive(ADDR, seq, Kind::Reply(&[1, 2]), now);
+        }
+
+        assert_eq!(
+            flow.anomalies[&owner("a").unwrap()],
+            Anomalies {
+                late: 1,
+                duplicates: 1,
+                reordered: 1,
+            }
+        );
+        assert_eq!(flow.anomalies[&owner("b").unwrap()].late, 1);
+        assert_eq!(flow.anomalies.len(), 2);
+        assert_eq!(flow.highest, Some(trace));
+    }
+
+    #[tokio::test]
+    async fn unused_sockets_are_closed() {
+        let mut sockets = HashMap::new();
+        let mut receivers = Vec::new();
+
+        for ttl in 1..=3 {
+            let settings = Settings {
+                ipv6: false,
+                socket_type: Socket::Datagram,
+                ttl: Some(ttl),
+                dont_fragment: false,
+                binding: Binding::default(),
+            };
+            let socket = Arc::new(EchoSocket {
+                socket: UdpSocket::bind("127.0.0.1:0").await.unwrap(),
+                settings: settings.clone(),
+                ident: 0,
+                flows: Mutex::default(),
+            });
+            let (stop, stopped) = oneshot::channel();
+
+            receivers.push(tokio::spawn(receive(socket.clone(), stopped)));
+            sockets.insert(
+                settings,
+                OpenSocket {
+                    socket,
+                    last_used: Instant::now(),
+                    _stop: stop,
+                },
+            );
+        }
+
+        let open = |sockets: &HashMap<Settings, OpenSocket>, ttl| {
+            sockets
+                .values()
+                .find(|open| open.socket.settings.ttl == Some(ttl))
+                .map(|open| open.socket.clone())
+        };
+
+        // the first is still used, the second has a request in flight
+        let later = Instant::now() + SOCKET_EXPIRY;
+        for open in sockets.values_mut() {
+            if open.socket.settings.ttl == Some(1) {
+                open.last_used = later;
+            }
+        }
+        let in_flight = open(&sockets, 2).unwrap();
+
+        prune(&mut sockets, later);
+
+        assert!(open(&sockets, 1).is_some());
+        assert!(open(&sockets, 2).is_some());
+        assert!(open(&sockets, 3).is_none());
+
+        let receiver = receivers.pop().unwrap();
+        tokio::time::timeout(Duration::from_secs(1), receiver)
+            .await
+            .expect("receiver of a closed socket keeps running")
+            .unwrap();
+
+        drop(in_flight);
+        prune(&mut sockets, later);
+        assert_eq!(sockets.len(), 1);
+    }
+
+    #[test]
+    fn receive_errors_back_off() {
+        assert_eq!(receive_backoff(1), MIN_RECEIVE_BACKOFF);
+        assert_eq!(receive_backoff(2), MIN_RECEIVE_BACKOFF * 2);
+        assert_eq!(receive_backoff(u32::MAX), MAX_RECEIVE_BACKOFF);
+    }
+
+    #[test]
+    fn sequence_wraps_around_and_forgets_old_requests() {
+        let mut flow = Flow::new();
+        flow.first_seq = u16::MAX - 1;
+
+        for _ in 0..HISTORY + 1 {
+            pending(&mut flow, None);
+        }
+
+        assert_eq!(flow.requests.len(), HISTORY);
+        assert_eq!(flow.first_seq, u16::MAX);
+        assert!(flow.request(u16::MAX - 1).is_none());
+        assert!(flow.request(HISTORY as u16 - 2).is_some());
+        assert!(is_before(u16::MAX, 0));
+        assert!(!is_before(0, u16::MAX));
+    }
+}
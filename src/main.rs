@@ -1,91 +1,231 @@
-use std::{net::IpAddr, sync::Arc, time::Duration};
+mod alert;
+mod bind;
+mod config;
+mod dns;
+mod fault;
+mod gateway;
+mod hook;
+mod http;
+mod icmp;
+mod metrics;
+mod pmtu;
+mod probe;
+mod resolve;
+mod state;
+mod stats;
+mod supervisor;
+mod tcp;
+mod tls;
+mod trace;
+mod udp;
+
+use std::{
+    net::{IpAddr, SocketAddr},
+    num::{NonZeroU8, NonZeroU32},
+    path::PathBuf,
+    sync::Arc,
+    time::Duration,
+};
 
-use clap::{Parser, ValueEnum};
-use file_rotate::TimeFrequency;
-use hickory_resolver::TokioResolver;
-use humantime::parse_duration;
-use ping::{Ping, SocketType};
+use alert::Alerts;
+use clap::{CommandFactory, Parser, error::ErrorKind};
+use config::{
+    Address, Config, Family, LogRotation, Pattern, ReplyLevel, Socket, parse_fwmark,
+    parse_nonzero_duration,
+};
+use fault::Health;
+use hickory_resolver::{TokioResolver, config::LookupIpStrategy};
+use hook::HookRunner;
+use icmp::Engine;
+use metrics::Metrics;
+use supervisor::{Context, Supervisor};
 use tokio::signal;
 use tokio::{runtime, task::spawn_blocking};
-use tracing::{error, info};
+use tracing::{error, info, warn};
 use tracing_subscriber_multi::{
     AnsiStripper, AppendCount, Compression, ContentLimit, DualWriter, FmtSubscriber, RotatingFile,
 };
 
 /// Pinger with logging to monitor network activity
-#[derive(Parser, Debug)]
+#[derive(Parser, Clone, Debug)]
 #[command(about, long_about = None)]
 struct Args {
-    /// address to ping
-    #[arg(short, long, value_parser = Args::parse_address)]
-    address: IpAddr,
+    /// path to a TOML config file, command line flags override its values
+    #[arg(short, long)]
+    config: Option<PathBuf>,
+
+    /// addresses or hostnames to ping, tcp://host:port to probe with a TCP handshake,
+    /// tls://host:port to check with a TLS handshake, udp://host:port to send a datagram to,
+    /// http(s):// URLs to request or dns://server/name?type=A to query, may be repeated or
+    /// given as a comma-separated list
+    #[arg(short, long, required_unless_present = "config", value_delimiter = ',')]
+    address: Vec<Address>,
 
-    /// interval between pings
-    #[arg(short, long, value_parser = parse_duration, default_value = "5s")]
-    interval: Duration,
+    /// interval between pings [default: 5s]
+    #[arg(short, long, value_parser = parse_nonzero_duration)]
+    interval: Option<Duration>,
 
-    /// log file rotation interval
-    #[arg(short, long, value_enum, default_value_t)]
-    log_rotation: LogRotation,
+    /// time to wait for a reply, at most the interval [default: 4s or the interval if shorter]
+    #[arg(short, long, value_parser = parse_nonzero_duration)]
+    timeout: Option<Duration>,
 
-    /// Socket type to use for pinging.
-    #[arg(short, long, value_enum, default_value_t)]
-    socket_type: Socket,
-}
+    /// maximum time before a hostname is resolved again, shorter DNS TTLs take precedence [default: 5m]
+    #[arg(long, value_parser = parse_nonzero_duration)]
+    resolve_interval: Option<Duration>,
 
-#[derive(ValueEnum, Clone, Default, Debug)]
-enum LogRotation {
-    /// Rotate every hour.
-    Hourly,
-    /// Rotate one time a day.
-    #[default]
-    Daily,
-    /// Rotate ones a week.
-    Weekly,
-    /// Rotate every month.
-    Monthly,
-    /// Rotate yearly.
-    Yearly,
-}
+    /// time to live of echo requests [default: system default]
+    #[arg(long)]
+    ttl: Option<NonZeroU8>,
 
-#[derive(ValueEnum, Clone, Default, Debug)]
-enum Socket {
-    /// Raw socket, may require root privileges.
-    #[default]
-    Raw,
-    /// Datagram socket.
-    Datagram,
-}
+    /// payload size of echo requests in bytes [default: 56]
+    #[arg(long)]
+    size: Option<usize>,
 
-impl Args {
-    fn parse_address(address_str: &str) -> Result<IpAddr, String> {
-        if let Ok(addr) = address_str.parse::<IpAddr>() {
-            return Ok(addr);
-        }
+    /// hex bytes echo request payloads are filled with, e.g. ff00 [default: counting bytes]
+    #[arg(long)]
+    pattern: Option<Pattern>,
+
+    /// set the don't fragment bit on echo requests
+    #[arg(long)]
+    dont_fragment: bool,
+
+    /// probes sent to every address on each tick [default: 1]
+    #[arg(long)]
+    burst: Option<NonZeroU32>,
+
+    /// time between the probes of a burst [default: 100ms]
+    #[arg(long, value_parser = parse_nonzero_duration)]
+    burst_gap: Option<Duration>,
+
+    /// discover the path MTU to ICMP targets on startup and periodically
+    #[arg(long)]
+    pmtu: bool,
+
+    /// time between path MTU discoveries [default: 10m]
+    #[arg(long, value_parser = parse_nonzero_duration)]
+    pmtu_interval: Option<Duration>,
+
+    /// don't trace the route when an outage starts and ends
+    #[arg(long)]
+    no_traceroute: bool,
+
+    /// probe every hop on the route each interval and log per-hop statistics, like mtr
+    #[arg(long)]
+    mtr: bool,
+
+    /// don't probe the default gateway, which locates outages in the LAN, at the ISP or at the host
+    #[arg(long)]
+    no_gateway: bool,
+
+    /// interface probes are sent through, e.g. to check one uplink of several [default: routing decides]
+    #[arg(long)]
+    interface: Option<String>,
+
+    /// local address probes are sent from, only addresses of its family are probed
+    #[arg(long)]
+    source: Option<IpAddr>,
+
+    /// firewall mark of probes for policy routing, decimal or 0x-prefixed hex
+    #[arg(long, value_parser = parse_fwmark)]
+    fwmark: Option<u32>,
+
+    /// use IPv4 addresses only
+    #[arg(short = '4', conflicts_with = "ipv6")]
+    ipv4: bool,
+
+    /// use IPv6 addresses only
+    #[arg(short = '6')]
+    ipv6: bool,
+
+    /// ping every resolved address of a hostname instead of just the first one
+    #[arg(long)]
+    all_addresses: bool,
+
+    /// log file rotation interval [default: daily]
+    #[arg(short, long, value_enum)]
+    log_rotation: Option<LogRotation>,
+
+    /// level successful replies are logged at [default: debug]
+    #[arg(long, value_enum)]
+    reply_level: Option<ReplyLevel>,
+
+    /// consecutive failed pings before a target is considered down [default: 3]
+    #[arg(long)]
+    down_after: Option<NonZeroU32>,
+
+    /// consecutive successful pings before a down target is considered up again [default: 2]
+    #[arg(long)]
+    up_after: Option<NonZeroU32>,
+
+    /// how often statistics are logged [default: 1m]
+    #[arg(long, value_parser = parse_nonzero_duration)]
+    stats_interval: Option<Duration>,
+
+    /// rolling windows statistics are computed over, as a comma-separated list [default: 1m,5m,1h]
+    #[arg(long, value_delimiter = ',', value_parser = parse_nonzero_duration)]
+    stats_windows: Option<Vec<Duration>>,
+
+    /// shell command run when a target goes down, see the README for its environment
+    #[arg(long)]
+    on_down: Option<String>,
+
+    /// shell command run when a target is up again
+    #[arg(long)]
+    on_up: Option<String>,
 
-        let resolver = TokioResolver::builder_tokio()
-            .map_err(|err| err.to_string())?
-            .build();
+    /// shell command run when a target is degraded
+    #[arg(long)]
+    on_degraded: Option<String>,
 
-        let res = runtime::Builder::new_current_thread()
-            .enable_all()
-            .build()
-            .map_err(|err| err.to_string())?
-            .block_on(resolver.lookup_ip(address_str))
-            .map_err(|err| err.to_string())?;
+    /// status codes HTTP probes accept, as a comma-separated list [default: any 2xx or 3xx]
+    #[arg(long, value_delimiter = ',')]
+    expect_status: Option<Vec<u16>>,
 
-        let address_opt = res.iter().next();
+    /// text the body of HTTP responses has to contain
+    #[arg(long)]
+    expect_body: Option<String>,
 
-        let Some(address) = address_opt else {
-            return Err("No IP address found".to_string());
-        };
+    /// answers DNS probes expect, as a comma-separated list [default: any answers]
+    #[arg(long, value_delimiter = ',')]
+    expect_answers: Option<Vec<String>>,
 
-        Ok(address)
+    /// datagram UDP probes send [default: pinger]
+    #[arg(long)]
+    payload: Option<String>,
+
+    /// text UDP replies have to contain [default: an echo of the payload]
+    #[arg(long)]
+    expect_reply: Option<String>,
+
+    /// degrade TLS targets once the certificate expires within this period [default: 14days]
+    #[arg(long, value_parser = parse_nonzero_duration)]
+    cert_expiry: Option<Duration>,
+
+    /// address to serve Prometheus metrics on, e.g. 127.0.0.1:9100
+    #[arg(long)]
+    metrics_listen: Option<SocketAddr>,
+
+    /// Socket type to use for pinging. [default: raw]
+    #[arg(short, long, value_enum)]
+    socket_type: Option<Socket>,
+}
+
+impl Args {
+    fn family(&self) -> Option<Family> {
+        match (self.ipv4, self.ipv6) {
+            (true, _) => Some(Family::Ipv4),
+            (_, true) => Some(Family::Ipv6),
+            _ => None,
+        }
     }
 }
 
 fn main() {
-    let args = Arc::new(Args::parse());
+    let args = Args::parse();
+    let gateway = gateway::default_gateway();
+
+    let config = config::load(&args, gateway.clone().unwrap_or_default())
+        .unwrap_or_else(|err| Args::command().error(ErrorKind::InvalidValue, err).exit());
 
     let subscriber = FmtSubscriber::builder()
         .with_env_filter(
@@ -96,9 +236,9 @@ fn main() {
         .with_writer(std::sync::Mutex::new(DualWriter::new(
             std::io::stderr(),
             AnsiStripper::new(RotatingFile::new(
-                "pinger.log",
-                AppendCount::new(3),
-                ContentLimit::Time(map_log_rotation(&args.log_rotation)),
+                &config.log.file,
+                AppendCount::new(config.log.keep),
+                ContentLimit::Time(config.log.rotation.time_frequency()),
                 Compression::OnRotate(0),
             )),
         )))
@@ -111,63 +251,136 @@ fn main() {
         .build()
         .unwrap();
 
-    runtime.block_on(async {
-        tokio::select! {
-            res = run(args) => {
-                if let Err(err) = res {
-                    error!("Error: {}", err);
-                }
-            },
-            _ = shutdown_signal() => {
-                info!("Shutting down");
-            }
-        }
-    });
-}
+    log_gateway(&config, &gateway);
 
-fn map_log_rotation(rotation: &LogRotation) -> TimeFrequency {
-    match rotation {
-        LogRotation::Hourly => TimeFrequency::Hourly,
-        LogRotation::Daily => TimeFrequency::Daily,
-        LogRotation::Weekly => TimeFrequency::Weekly,
-        LogRotation::Monthly => TimeFrequency::Monthly,
-        LogRotation::Yearly => TimeFrequency::Yearly,
+    if let Err(err) = runtime.block_on(run(args, config)) {
+        error!("Error: {}", err);
     }
 }
 
-fn map_socket_type(socket_type: &Socket) -> SocketType {
-    match socket_type {
-        Socket::Raw => SocketType::RAW,
-        Socket::Datagram => SocketType::DGRAM,
+async fn run(args: Args, config: Config) -> Result<(), String> {
+    let mut resolver_builder = TokioResolver::builder_tokio().map_err(|err| err.to_string())?;
+    // both families are needed to probe all addresses or filter by family
+    resolver_builder.options_mut().ip_strategy = LookupIpStrategy::Ipv4AndIpv6;
+    // targets keep their addresses for the TTL themselves, so every lookup
+    // they time goes out to the server
+    resolver_builder.options_mut().cache_size = 0;
+    let resolver = resolver_builder.build();
+
+    let metrics = Arc::new(Metrics::default());
+
+    if let Some(listen) = config.metrics_listen {
+        metrics::serve(listen, metrics.clone()).await?;
     }
-}
 
-async fn run(args: Arc<Args>) -> Result<(), String> {
-    let mut interval = tokio::time::interval(args.interval);
-    let addr = Arc::new(args.address);
+    let alerts = Alerts::new(config.webhooks.clone(), resolver.clone());
+    let hooks = HookRunner::new(&config.hooks);
+
+    let mut supervisor = Supervisor::new(Context {
+        resolver,
+        metrics,
+        alerts,
+        hooks,
+        icmp: Engine::default(),
+        health: Health::default(),
+    });
+    supervisor.update(config.targets.clone());
+
+    let args = Arc::new(args);
+
+    #[cfg(unix)]
+    let mut hangup = signal::unix::signal(signal::unix::SignalKind::hangup())
+        .expect("failed to install signal handler");
 
-    info!("Pinging {}", addr);
+    let shutdown = shutdown_signal();
+    tokio::pin!(shutdown);
 
     loop {
-        interval.tick().await;
+        #[cfg(unix)]
+        let reload = hangup.recv();
+
+        #[cfg(not(unix))]
+        let reload = std::future::pending::<Option<()>>();
 
-        let addr_clone = addr.clone();
-        let args_clone = args.clone();
+        tokio::select! {
+            Some(()) = supervisor.restart_failed() => {}
+            _ = reload => {
+                reload_config(args.clone(), &config, &mut supervisor).await
+            }
+            _ = &mut shutdown => {
+                info!("Shutting down");
+                supervisor.shutdown();
+
+                return Ok(());
+            }
+        }
+    }
+}
 
-        let res = spawn_blocking(move || {
-            let socket_type = map_socket_type(&args_clone.socket_type);
+/// Applies the target changes of the reloaded configuration. Settings shared
+/// by all targets are only compared against the `current` ones.
+async fn reload_config(args: Arc<Args>, current: &Config, supervisor: &mut Supervisor) {
+    info!("Reloading configuration");
 
-            let mut pinger_builder = Ping::new(*addr_clone);
-            let pinger = pinger_builder.socket_type(socket_type);
+    let res = spawn_blocking(move || {
+        let gateway = gateway::default_gateway();
 
-            return pinger.send();
-        })
-        .await
-        .map_err(|err| err.to_string())?;
+        config::load(&args, gateway.clone().unwrap_or_default()).map(|config| (config, gateway))
+    })
+    .await
+    .unwrap_or_else(|err| Err(err.to_string()));
 
-        if let Err(err) = res {
-            error!("Failed to ping {}, error: {}", addr, err);
+    let config = match res {
+        Ok((config, gateway)) => {
+            log_gateway(&config, &gateway);
+            config
         }
+        Err(err) => {
+            error!(
+                "Failed to reload configuration, keeping the current one: {}",
+                err
+            );
+            return;
+        }
+    };
+
+    if config.log != current.log {
+        warn!("Log settings changed, restart pinger to apply them");
+    }
+
+    if config.metrics_listen != current.metrics_listen {
+        warn!("Metrics endpoint changed, restart pinger to apply it");
+    }
+
+    if config.webhooks != current.webhooks {
+        warn!("Webhooks changed, restart pinger to apply them");
+    }
+
+    if config.hooks != current.hooks {
+        warn!("Hook settings changed, restart pinger to apply them");
+    }
+
+    let summary = supervisor.update(config.targets);
+
+    info!(
+        "Configuration reloaded: {} started, {} stopped, {} unchanged",
+        summary.started, summary.stopped, summary.unchanged
+    );
+}
+
+/// Warns when the default gateway should be probed but wasn't found.
+fn log_gateway(config: &Config, gateway: &Result<Option<IpAddr>, String>) {
+    if !config.gateway {
+        return;
+    }
+
+    match gateway {
+        Ok(Some(_)) => {}
+        Ok(None) => warn!("No default gateway found, outages can't be located"),
+        Err(err) => warn!(
+            "Failed to find the default gateway, outages can't be located: {}",
+            err
+        ),
     }
 }
 
@@ -0,0 +1,462 @@
+use std::{
+    collections::BTreeMap,
+    fmt::{self, Write as _},
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    time::timeout,
+};
+use tracing::{debug, info};
+
+use crate::{
+    config::{Address, Target},
+    state::State,
+};
+
+/// Upper bounds of the RTT histogram buckets in seconds.
+const RTT_BUCKETS: [f64; 12] = [
+    0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0,
+];
+
+const MAX_REQUEST_SIZE: usize = 8192;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Per-target metrics exposed in the Prometheus text format.
+#[derive(Default)]
+pub struct Metrics {
+    targets: Mutex<BTreeMap<TargetKey, TargetMetrics>>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct TargetKey {
+    target: String,
+    label: String,
+}
+
+impl TargetKey {
+    pub fn new(target: &Target) -> Self {
+        Self::from_parts(&target.address, target.label.as_deref())
+    }
+
+    pub fn from_parts(address: &Address, label: Option<&str>) -> Self {
+        Self {
+            target: address.to_string(),
+            label: label.unwrap_or_default().to_string(),
+        }
+    }
+}
+
+impl fmt::Display for TargetKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "target=\"{}\",label=\"{}\"",
+            escape_label(&self.target),
+            escape_label(&self.label)
+        )
+    }
+}
+
+#[derive(Default)]
+struct TargetMetrics {
+    sent: u64,
+    received: u64,
+    failed: BTreeMap<&'static str, u64>,
+    dns_failures: u64,
+    rtt_buckets: [u64; RTT_BUCKETS.len()],
+    rtt_sum: f64,
+    state: Option<State>,
+    last_success: Option<SystemTime>,
+    /// Durations of the steps of the last successful probe.
+    phases: BTreeMap<&'static str, Duration>,
+    /// Last discovered path MTU of every address.
+    path_mtu: BTreeMap<IpAddr, u32>,
+}
+
+impl Metrics {
+    fn with_target(&self, key: &TargetKey, f: impl FnOnce(&mut TargetMetrics)) {
+        let mut targets = self.targets.lock().unwrap();
+        f(targets.entry(key.clone()).or_default());
+    }
+
+    pub fn record_reply(&self, key: &TargetKey, rtt: Duration) {
+        self.with_target(key, |metrics| {
+            let rtt = rtt.as_secs_f64();
+
+            metrics.sent += 1;
+            metrics.received += 1;
+            metrics.rtt_sum += rtt;
+            metrics.last_success = Some(SystemTime::now());
+
+            for (bucket, le) in metrics.rtt_buckets.iter_mut().zip(RTT_BUCKETS) {
+                if rtt <= le {
+                    *bucket += 1;
+                }
+            }
+        });
+    }
+
+    pub fn record_failure(&self, key: &TargetKey, kind: &'static str) {
+        self.with_target(key, |metrics| {
+            metrics.sent += 1;
+            *metrics.failed.entry(kind).or_default() += 1;
+        });
+    }
+
+    pub fn record_dns_failure(&self, key: &TargetKey) {
+        self.with_target(key, |metrics| metrics.dns_failures += 1);
+    }
+
+    pub fn set_phases(&self, key: &TargetKey, phases: &[(&'static str, Duration)]) {
+        self.with_target(key, |metrics| {
+            metrics.phases.extend(phases.iter().copied());
+        });
+    }
+
+    pub fn set_path_mtu(&self, key: &TargetKey, addr: IpAddr, mtu: u32) {
+        self.with_target(key, |metrics| {
+            metrics.path_mtu.insert(addr, mtu);
+        });
+    }
+
+    pub fn set_state(&self, key: &TargetKey, state: State) {
+        self.with_target(key, |metrics| metrics.state = Some(state));
+    }
+
+    /// Drops the metrics of a target that is no longer probed.
+    pub fn remove(&self, key: &TargetKey) {
+        self.targets.lock().unwrap().remove(key);
+    }
+
+    /// Renders all metrics in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let targets = self.targets.lock().unwrap();
+        let mut out = String::new();
+
+        header(
+            &mut out,
+            "pinger_probes_sent_total",
+            "counter",
+            "Probes sent to the target.",
+        );
+        for (key, metrics) in targets.iter() {
+            let _ = writeln!(out, "pinger_probes_sent_total{{{key}}} {}", metrics.sent);
+        }
+
+        header(
+            &mut out,
+            "pinger_probes_received_total",
+            "counter",
+            "Probes the target replied to.",
+        );
+        for (key, metrics) in targets.iter() {
+            let _ = writeln!(
+                out,
+                "pinger_probes_received_total{{{key}}} {}",
+                metrics.received
+            );
+        }
+
+        header(
+            &mut out,
+            "pinger_probes_failed_total",
+            "counter",
+            "Failed probes by error kind.",
+        );
+        for (key, metrics) in targets.iter() {
+            for (kind, count) in &metrics.failed {
+                let _ = writeln!(
+                    out,
+                    "pinger_probes_failed_total{{{key},kind=\"{kind}\"}} {count}"
+                );
+            }
+        }
+
+        header(
+            &mut out,
+            "pinger_dns_failures_total",
+            "counter",
+            "Failed DNS resolutions of the target hostname.",
+        );
+        for (key, metrics) in targets.iter() {
+            let _ = writeln!(
+                out,
+                "pinger_dns_failures_total{{{key}}} {}",
+                metrics.dns_failures
+            );
+        }
+
+        header(
+            &mut out,
+            "pinger_rtt_seconds",
+            "histogram",
+            "Round-trip time of replies.",
+        );
+        for (key, metrics) in targets.iter() {
+            for (count, le) in metrics.rtt_buckets.iter().zip(RTT_BUCKETS) {
+                let _ = writeln!(
+                    out,
+                    "pinger_rtt_seconds_bucket{{{key},le=\"{le}\"}} {count}"
+                );
+            }
+            let _ = writeln!(
+                out,
+                "pinger_rtt_seconds_bucket{{{key},le=\"+Inf\"}} {}",
+                metrics.received
+            );
+            let _ = writeln!(out, "pinger_rtt_seconds_sum{{{key}}} {}", metrics.rtt_sum);
+            let _ = writeln!(
+                out,
+                "pinger_rtt_seconds_count{{{key}}} {}",
+                metrics.received
+            );
+        }
+
+        header(
+            &mut out,
+            "pinger_probe_phase_seconds",
+            "gauge",
+            "Duration of the steps of the last successful probe, like connect or TLS handshake.",
+        );
+        for (key, metrics) in targets.iter() {
+            for (phase, duration) in &metrics.phases {
+                let _ = writeln!(
+                    out,
+                    "pinger_probe_phase_seconds{{{key},phase=\"{phase}\"}} {}",
+                    duration.as_secs_f64()
+                );
+            }
+        }
+
+        header(
+            &mut out,
+            "pinger_path_mtu_bytes",
+            "gauge",
+            "Last discovered path MTU to the address.",
+        );
+        for (key, metrics) in targets.iter() {
+            for (addr, mtu) in &metrics.path_mtu {
+                let _ = writeln!(
+                    out,
+                    "pinger_path_mtu_bytes{{{key},address=\"{addr}\"}} {mtu}"
+                );
+            }
+        }
+
+        header(
+            &mut out,
+            "pinger_target_state",
+            "gauge",
+            "Current state of the target, 1 for the active state.",
+        );
+        for (key, metrics) in targets.iter() {
+            let Some(current) = metrics.state else {
+                continue;
+            };
+
+            for state in [State::Up, State::Degraded, State::Down] {
+                let _ = writeln!(
+                    out,
+                    "pinger_target_state{{{key},state=\"{state}\"}} {}",
+                    u8::from(state == current)
+                );
+            }
+        }
+
+        header(
+            &mut out,
+            "pinger_last_success_timestamp_seconds",
+            "gauge",
+            "Unix time of the last reply from the target.",
+        );
+        for (key, metrics) in targets.iter() {
+            let Some(last_success) = metrics.last_success else {
+                continue;
+            };
+
+            let timestamp = last_success
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs_f64();
+
+            let _ = writeln!(
+                out,
+                "pinger_last_success_timestamp_seconds{{{key}}} {timestamp:.3}"
+            );
+        }
+
+        out
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {name} {help}");
+    let _ = writeln!(out, "# TYPE {name} {kind}");
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Binds the metrics endpoint, the listener is served from a background task.
+pub async fn serve(listen: SocketAddr, metrics: Arc<Metrics>) -> Result<(), String> {
+    let listener = TcpListener::bind(listen)
+        .await
+        .map_err(|err| format!("failed to bind metrics endpoint {listen}: {err}"))?;
+
+    info!("Serving metrics on http://{}/metrics", listen);
+
+    tokio::spawn(async move {
+        loop {
+            let (stream, peer) = match listener.accept().await {
+                Ok(conn) => conn,
+                Err(err) => {
+                    debug!("Failed to accept metrics connection, error: {}", err);
+                    continue;
+                }
+            };
+
+            let metrics = metrics.clone();
+
+            tokio::spawn(async move {
+                if let Err(err) = handle(stream, &metrics).await {
+                    debug!("Failed to serve metrics to {}, error: {}", peer, err);
+                }
+            });
+        }
+    });
+
+    Ok(())
+}
+
+async fn handle(mut stream: TcpStream, metrics: &Metrics) -> Result<(), String> {
+    let mut request = Vec::new();
+    let mut buf = [0; 1024];
+
+    while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+        if request.len() > MAX_REQUEST_SIZE {
+            return Err("request too large".to_string());
+        }
+
+        let read = timeout(REQUEST_TIMEOUT, stream.read(&mut buf))
+            .await
+            .map_err(|_| "request timed out".to_string())?
+            .map_err(|err| err.to_string())?;
+
+        if read == 0 {
+            return Err("connection closed".to_string());
+        }
+
+        request.extend_from_slice(&buf[..read]);
+    }
+
+    let request_line = request
+        .split(|&byte| byte == b'\r')
+        .next()
+        .unwrap_or_default();
+
+    let (status, body) = match request_line.split(|&byte| byte == b' ').collect::<Vec<_>>()[..] {
+        [b"GET", b"/metrics", _] => ("200 OK", metrics.render()),
+        [b"GET", _, _] => ("404 Not Found", "Not Found\n".to_string()),
+        _ => ("405 Method Not Allowed", "Method Not Allowed\n".to_string()),
+    };
+
+    let response = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    );
+
+    stream
+        .write_all(response.as_bytes())
+        .await
+        .map_err(|err| err.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(label: &str) -> TargetKey {
+        TargetKey::from_parts(&"192.0.2.1".parse().unwrap(), Some(label))
+    }
+
+    /// Returns the value of the sample with exactly these name and labels.
+    fn sample(out: &str, series: &str) -> String {
+        out.lines()
+            .find_map(|line| line.strip_prefix(series)?.strip_prefix(' '))
+            .unwrap_or_else(|| panic!("missing {series} in\n{out}"))
+            .to_string()
+    }
+
+    #[test]
+    fn rtt_histogram_is_cumulative() {
+        let metrics = Metrics::default();
+        let key = key("");
+
+        for ms in [3, 30, 3000, 10_000] {
+            metrics.record_reply(&key, Duration::from_millis(ms));
+        }
+        metrics.record_failure(&key, "timeout");
+
+        let out = metrics.render();
+        let bucket = |le: &str| {
+            sample(
+                &out,
+                &format!("pinger_rtt_seconds_bucket{{{key},le=\"{le}\"}}"),
+            )
+        };
+
+        assert_eq!(bucket("0.001"), "0");
+        assert_eq!(bucket("0.005"), "1");
+        assert_eq!(bucket("0.025"), "1");
+        assert_eq!(bucket("0.05"), "2");
+        assert_eq!(bucket("2.5"), "2");
+        assert_eq!(bucket("5"), "3");
+        // replies above the largest bucket only count for +Inf, lost probes
+        // don't count at all
+        assert_eq!(bucket("+Inf"), "4");
+        assert_eq!(
+            sample(&out, &format!("pinger_rtt_seconds_count{{{key}}}")),
+            "4"
+        );
+        assert_eq!(
+            sample(&out, &format!("pinger_rtt_seconds_sum{{{key}}}"))
+                .parse::<f64>()
+                .unwrap(),
+            13.033
+        );
+        assert_eq!(
+            sample(&out, &format!("pinger_probes_sent_total{{{key}}}")),
+            "5"
+        );
+        assert_eq!(
+            sample(
+                &out,
+                &format!("pinger_probes_failed_total{{{key},kind=\"timeout\"}}")
+            ),
+            "1"
+        );
+    }
+
+    #[test]
+    fn labels_are_escaped() {
+        let metrics = Metrics::default();
+        metrics.record_dns_failure(&key("say \"hi\"\\\nbye"));
+
+        assert_eq!(
+            sample(
+                &metrics.render(),
+                r#"pinger_dns_failures_total{target="192.0.2.1",label="say \"hi\"\\\nbye"}"#
+            ),
+            "1"
+        );
+    }
+}
@@ -0,0 +1,67 @@
+use std::net::IpAddr;
+
+use crate::{config::Target, icmp::Engine, probe::Failure};
+
+/// Sizes of the IP and ICMP headers preceding the echo request payload.
+const IPV4_OVERHEAD: u32 = 20 + 8;
+const IPV6_OVERHEAD: u32 = 40 + 8;
+/// Smallest MTU every link has to support, RFC 791 and RFC 8200.
+const IPV4_MIN_MTU: u32 = 68;
+const IPV6_MIN_MTU: u32 = 1280;
+/// Largest IP packet, its length field has 16 bits.
+const MAX_MTU: u32 = 65535;
+/// Probes of a size sent before a timeout is taken as too large, so a single
+/// lost packet doesn't lower the result.
+const ATTEMPTS: usize = 2;
+
+/// Finds the largest packet that reaches the address unfragmented, by a
+/// binary search over echo requests with the don't fragment bit set.
+///
+/// Sizes rejected with an ICMP error or by the kernel's known path MTU fail
+/// right away, sizes dropped silently on the way time out.
+pub async fn discover(icmp: &Engine, target: &Target, addr: IpAddr) -> Result<u32, Failure> {
+    let (overhead, min_mtu) = if addr.is_ipv4() {
+        (IPV4_OVERHEAD, IPV4_MIN_MTU)
+    } else {
+        (IPV6_OVERHEAD, IPV6_MIN_MTU)
+    };
+
+    let mut probe = Target {
+        dont_fragment: true,
+        ..target.clone()
+    };
+
+    // the smallest size has to pass, otherwise the host is just unreachable
+    probe.size = (min_mtu - overhead) as usize;
+    icmp.ping(&probe, addr).await?;
+
+    let (mut fits, mut too_large) = (min_mtu, MAX_MTU + 1);
+
+    while too_large - fits > 1 {
+        let mtu = fits + (too_large - fits) / 2;
+        probe.size = (mtu - overhead) as usize;
+
+        if passes(icmp, &probe, addr).await? {
+            fits = mtu;
+        } else {
+            too_large = mtu;
+        }
+    }
+
+    Ok(fits)
+}
+
+/// Sends echo requests of the probe's size until one gets a reply. Errors
+/// other than the packet being too large abort the discovery.
+async fn passes(icmp: &Engine, probe: &Target, addr: IpAddr) -> Result<bool, Failure> {
+    for _ in 0..ATTEMPTS {
+        match icmp.ping(probe, addr).await {
+            Ok(_) => return Ok(true),
+            Err(err) if err.kind == "mtu" => return Ok(false),
+            Err(err) if err.kind == "timeout" => {}
+            Err(err) => return Err(err),
+        }
+    }
+
+    Ok(false)
+}
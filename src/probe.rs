@@ -0,0 +1,612 @@
+use std::{
+    collections::BTreeMap,
+    fmt,
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use humantime::format_duration;
+use tokio::{task::JoinSet, time::MissedTickBehavior};
+use tracing::{Instrument, debug, error, info, trace, warn};
+
+use crate::{
+    alert::Event,
+    config::{Address, Probe, ReplyLevel, Target},
+    dns,
+    fault::Fault,
+    http,
+    icmp::{Engine, Response},
+    metrics::{Metrics, TargetKey},
+    pmtu,
+    resolve::{Refresh, Resolution},
+    state::{Outcome, State, Tracker, Transition},
+    stats::{Stats, Summary, log_summary},
+    supervisor::Context,
+    tcp, tls,
+    trace::{self, Change, Hop, Route},
+    udp,
+};
+
+/// A probe that got no valid reply.
+#[derive(Debug)]
+pub struct Failure {
+    /// Short classification used as the failure metrics label.
+    pub kind: &'static str,
+    pub message: String,
+}
+
+impl Failure {
+    pub fn new(kind: &'static str, message: impl fmt::Display) -> Self {
+        Self {
+            kind,
+            message: message.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.message.fmt(f)
+    }
+}
+
+/// A successful probe.
+#[derive(Debug)]
+pub struct Reply {
+    pub rtt: Duration,
+    /// Probe specific details added to the reply log.
+    pub detail: Option<String>,
+    /// Problem found despite the reply, degrades the target.
+    pub warning: Option<String>,
+    /// Durations of the steps the probe took, exported as metrics.
+    pub phases: Vec<(&'static str, Duration)>,
+}
+
+impl From<Duration> for Reply {
+    fn from(rtt: Duration) -> Self {
+        Self {
+            rtt,
+            detail: None,
+            warning: None,
+            phases: Vec::new(),
+        }
+    }
+}
+
+pub async fn probe(
+    target: Arc<Target>,
+    stats: Arc<Mutex<Stats>>,
+    tracker: Arc<Mutex<Tracker>>,
+    context: Context,
+) {
+    let Context {
+        resolver,
+        metrics,
+        alerts,
+        hooks,
+        icmp,
+        health,
+    } = context;
+    let key = TargetKey::new(&target);
+    let mut interval = tokio::time::interval(target.interval);
+    // a tick delayed by a slow DNS lookup doesn't cause a burst of probes
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let mut stats_interval = tokio::time::interval_at(
+        tokio::time::Instant::now() + target.stats.interval,
+        target.stats.interval,
+    );
+    let mut resolution = Resolution::new(
+        target.address.host.clone(),
+        target.family,
+        resolver,
+        target.resolve_interval,
+    );
+
+    let pmtu = target.pmtu && target.address.probe == Probe::Icmp;
+    // discoveries take several round trips, so they run next to the probes
+    let mut discoveries = JoinSet::new();
+    let mut next_discovery = Instant::now();
+    let mut traces = JoinSet::new();
+    let mut routes = BTreeMap::<IpAddr, Route>::new();
+    let mut rounds = JoinSet::new();
+
+    info!("Probing {}", target.address);
+
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {}
+            _ = stats_interval.tick() => {
+                log_summary(&stats.lock().unwrap(), &target.stats.windows);
+
+                for (addr, route) in &mut routes {
+                    info!(
+                        "Route to {} over the last {}",
+                        addr,
+                        format_duration(target.stats.interval)
+                    );
+                    log_hops(&route.take());
+                }
+                continue;
+            }
+            Some(res) = discoveries.join_next(), if !discoveries.is_empty() => {
+                if let Ok((addr, res)) = res {
+                    record_pmtu(&stats, &metrics, &key, addr, res);
+                }
+                continue;
+            }
+            Some(_) = traces.join_next(), if !traces.is_empty() => continue,
+            Some(res) = rounds.join_next(), if !rounds.is_empty() => {
+                if let Ok((addr, res)) = res {
+                    record_round(routes.entry(addr).or_default(), addr, res);
+                }
+                continue;
+            }
+        }
+
+        // the outage itself is reported once by the state transition
+        let down = tracker.lock().unwrap().state() == State::Down;
+
+        // only ticks that looked the host up have a DNS phase
+        let dns = match resolution.refresh().await {
+            Refresh::Resolved(took) => Some(took),
+            Refresh::Cached => None,
+            Refresh::Failed => {
+                metrics.record_dns_failure(&key);
+                None
+            }
+        };
+
+        let addresses = if target.all_addresses {
+            resolution.addresses().to_vec()
+        } else {
+            resolution.address().into_iter().collect()
+        };
+
+        if pmtu && discoveries.is_empty() && Instant::now() >= next_discovery {
+            next_discovery = Instant::now() + target.pmtu_interval;
+
+            for &addr in &addresses {
+                let (target, icmp) = (target.clone(), icmp.clone());
+                discoveries.spawn(
+                    async move { (addr, pmtu::discover(&icmp, &target, addr).await) }
+                        .in_current_span(),
+                );
+            }
+        }
+
+        // a round ends shortly after the timeout, so it only lags behind when
+        // the probes of the last tick are still running
+        if target.mtr && rounds.is_empty() {
+            routes.retain(|addr, _| addresses.contains(addr));
+
+            for &addr in &addresses {
+                let max_hops = routes.get(&addr).map_or(trace::MAX_HOPS, Route::max_hops);
+                let (target, icmp) = (target.clone(), icmp.clone());
+                rounds.spawn(
+                    async move { (addr, trace::round(&icmp, &target, addr, max_hops).await) }
+                        .in_current_span(),
+                );
+            }
+        }
+
+        // without an address the tick counts as lost, so a host that never
+        // resolves still goes down
+        let (outcome, lost) = if addresses.is_empty() {
+            let burst = target.burst.get();
+
+            for _ in 0..burst {
+                stats.lock().unwrap().record(None, None);
+                metrics.record_failure(&key, "dns");
+            }
+
+            if down {
+                debug!("Failed to probe {}, no address resolved", target.address);
+            } else {
+                warn!("Failed to probe {}, no address resolved", target.address);
+            }
+
+            (Outcome::Failure, u64::from(burst))
+        } else {
+            let bursts = addresses
+                .iter()
+                .map(|&addr| {
+                    tokio::spawn(
+                        send_burst(target.clone(), icmp.clone(), addr, dns).in_current_span(),
+                    )
+                })
+                .collect::<Vec<_>>();
+
+            let mut reachable = 0;
+            let mut warned = 0;
+            let mut lost = 0;
+
+            for (addr, burst) in addresses.iter().zip(bursts) {
+                // a panicking probe fails its burst, not the whole target
+                let results = burst.await.unwrap_or_else(|err| {
+                    error!("Probes of {} failed, error: {}", addr, err);
+                    (0..target.burst.get())
+                        .map(|_| Err(Failure::new("internal", &err)))
+                        .collect()
+                });
+                let endpoint = endpoint(&target.address.probe, *addr);
+                let mut summary = Summary::default();
+
+                if target.address.probe == Probe::Icmp {
+                    let anomalies = icmp.take_anomalies(&target, *addr);
+
+                    if !anomalies.is_empty() {
+                        stats.lock().unwrap().record_anomalies(*addr, anomalies);
+                    }
+                }
+
+                for res in &results {
+                    let rtt = res.as_ref().ok().map(|reply| reply.rtt);
+                    stats.lock().unwrap().record(Some(*addr), rtt);
+                    summary.add(Some(*addr), rtt);
+
+                    match res {
+                        Ok(reply) => {
+                            metrics.record_reply(&key, reply.rtt);
+                            metrics.set_phases(&key, &reply.phases);
+                        }
+                        Err(err) => metrics.record_failure(&key, err.kind),
+                    }
+                }
+
+                if summary.received > 0 {
+                    reachable += 1;
+                } else {
+                    lost += summary.sent;
+                }
+
+                let action = target.address.probe.action();
+                let warning = results
+                    .iter()
+                    .rev()
+                    .find_map(|res| res.as_ref().ok()?.warning.as_deref());
+
+                if let Some(warning) = warning {
+                    warned += 1;
+
+                    if down {
+                        debug!("Reply from {} with warning: {}", endpoint, warning);
+                    } else {
+                        warn!("Reply from {} with warning: {}", endpoint, warning);
+                    }
+                }
+
+                if let [res] = &results[..] {
+                    match res {
+                        Ok(reply) => log_reply(target.reply_level, &endpoint, reply),
+                        Err(err) if down => {
+                            debug!("Failed to {} {}, error: {}", action, endpoint, err)
+                        }
+                        Err(err) => warn!("Failed to {} {}, error: {}", action, endpoint, err),
+                    }
+
+                    continue;
+                }
+
+                for res in &results {
+                    match res {
+                        Ok(reply) => log_reply(ReplyLevel::Trace, &endpoint, reply),
+                        Err(err) => debug!("Failed to {} {}, error: {}", action, endpoint, err),
+                    }
+                }
+
+                let last_err = results.iter().rev().find_map(|res| res.as_ref().err());
+
+                match last_err {
+                    None => log_burst(target.reply_level, &endpoint, &summary),
+                    Some(err) if down => {
+                        debug!("Burst to {} {}, last error: {}", endpoint, summary, err)
+                    }
+                    Some(err) => warn!("Burst to {} {}, last error: {}", endpoint, summary, err),
+                }
+            }
+
+            // warnings degrade the target without taking it down
+            let outcome = if reachable == addresses.len() && warned == 0 {
+                Outcome::Success
+            } else if reachable > 0 {
+                Outcome::Partial
+            } else {
+                Outcome::Failure
+            };
+
+            if target.all_addresses && reachable < addresses.len() {
+                debug!(
+                    "Host {} reachable via {} of {} addresses",
+                    target.address,
+                    reachable,
+                    addresses.len()
+                );
+            }
+
+            (outcome, lost)
+        };
+
+        health.update(&key, target.gateway, outcome == Outcome::Failure);
+
+        let mut tracker = tracker.lock().unwrap();
+
+        if let Some(transition) = tracker.update(outcome, lost) {
+            log_transition(&target.address, &transition);
+
+            let fault = (transition.to == State::Down)
+                .then(|| health.locate(&key))
+                .flatten();
+
+            if let Some(fault) = fault {
+                log_fault(&target.address, fault);
+            }
+
+            if target.traceroute && (transition.to == State::Down || transition.from == State::Down)
+            {
+                let when = if transition.to == State::Down {
+                    "at the start of the outage"
+                } else {
+                    "after the outage"
+                };
+
+                for &addr in &addresses {
+                    traces.spawn(
+                        log_route(icmp.clone(), target.clone(), addr, when).in_current_span(),
+                    );
+                }
+            }
+
+            let event = Event::new(&target, &transition, fault);
+            hooks.run(&target.hooks, event.clone());
+            alerts.notify(event);
+        }
+
+        metrics.set_state(&key, tracker.state());
+    }
+}
+
+/// Traces the route to the address and logs every hop.
+async fn log_route(icmp: Engine, target: Arc<Target>, addr: IpAddr, when: &'static str) {
+    match trace::snapshot(&icmp, &target, addr).await {
+        Ok(hops) => {
+            info!("Route to {} {}", addr, when);
+            log_hops(&hops);
+        }
+        Err(err) => warn!("Failed to trace the route to {}, error: {}", addr, err),
+    }
+}
+
+/// Logs a line per hop, the hop where loss to the destination starts is
+/// logged as a warning.
+fn log_hops(hops: &[Hop]) {
+    let loss_start = trace::loss_start(hops);
+
+    for (idx, hop) in hops.iter().enumerate() {
+        if Some(idx) == loss_start {
+            warn!("{:>2}  {}, loss starts here", idx + 1, hop);
+        } else {
+            info!("{:>2}  {}", idx + 1, hop);
+        }
+    }
+}
+
+/// Adds a round of continuous route monitoring and logs how the route
+/// changed.
+fn record_round(
+    route: &mut Route,
+    addr: IpAddr,
+    res: Result<Vec<Result<Response, Failure>>, Failure>,
+) {
+    let responses = match res {
+        Ok(responses) => responses,
+        Err(err) => {
+            debug!("Failed to trace the route to {}, error: {}", addr, err);
+            return;
+        }
+    };
+
+    for change in route.add(&responses) {
+        match change {
+            Change::Length { from: None, to } => {
+                info!("Route to {} reaches it at hop {}", addr, to)
+            }
+            Change::Length {
+                from: Some(from),
+                to,
+            } => warn!(
+                "Route to {} changed, it is reached at hop {} instead of {}",
+                addr, to, from
+            ),
+            Change::Responder { hop, addr: router } => warn!(
+                "Route to {} changed, {} answered at hop {}",
+                addr, router, hop
+            ),
+        }
+    }
+}
+
+/// Stores a discovered path MTU and logs it when it is new or changed.
+fn record_pmtu(
+    stats: &Mutex<Stats>,
+    metrics: &Metrics,
+    key: &TargetKey,
+    addr: IpAddr,
+    res: Result<u32, Failure>,
+) {
+    let mtu = match res {
+        Ok(mtu) => mtu,
+        Err(err) => {
+            debug!(
+                "Failed to discover the path MTU to {}, error: {}",
+                addr, err
+            );
+            return;
+        }
+    };
+
+    metrics.set_path_mtu(key, addr, mtu);
+
+    match stats.lock().unwrap().set_pmtu(addr, mtu) {
+        None => info!("Path MTU to {} is {} bytes", addr, mtu),
+        Some(previous) if previous != mtu => warn!(
+            "Path MTU to {} changed from {} to {} bytes",
+            addr, previous, mtu
+        ),
+        Some(_) => debug!("Path MTU to {} is still {} bytes", addr, mtu),
+    }
+}
+
+fn log_transition(host: &Address, transition: &Transition) {
+    let duration = format_duration(Duration::from_secs(transition.duration.as_secs()));
+
+    match (transition.from, transition.to) {
+        (_, State::Down) => error!(
+            "Host {} is down after {} consecutive failures, {} probes lost",
+            host, transition.failures, transition.lost
+        ),
+        (State::Down, State::Degraded) => warn!(
+            "Host {} is partially reachable again after an outage of {}, {} probes lost",
+            host, duration, transition.lost
+        ),
+        (_, State::Degraded) => warn!("Host {} is degraded", host),
+        (State::Down, State::Up) => info!(
+            "Host {} is up again after an outage of {}, {} probes lost",
+            host, duration, transition.lost
+        ),
+        (_, State::Up) => info!(
+            "Host {} is up again after being degraded for {}, {} probes lost",
+            host, duration, transition.lost
+        ),
+    }
+}
+
+fn log_fault(host: &Address, fault: Fault) {
+    match fault {
+        Fault::Lan => warn!(
+            "Outage of {} is in the local LAN, the default gateway is unreachable",
+            host
+        ),
+        Fault::Upstream => warn!(
+            "Outage of {} is at the ISP or upstream, the default gateway replies but no other target does",
+            host
+        ),
+        Fault::Remote => warn!(
+            "Outage of {} affects the remote host only, other targets still reply",
+            host
+        ),
+    }
+}
+
+/// Sends a single probe of the target's kind to the address. `dns` is the
+/// time it took to resolve the address on this tick, `None` if it was known.
+async fn send(
+    target: &Target,
+    icmp: &Engine,
+    addr: IpAddr,
+    dns: Option<Duration>,
+) -> Result<Reply, Failure> {
+    match &target.address.probe {
+        Probe::Icmp => icmp.ping(target, addr).await,
+        Probe::Tcp { port } => tcp::connect(
+            SocketAddr::new(addr, *port),
+            &target.binding,
+            target.timeout,
+        )
+        .await
+        .map(Reply::from),
+        Probe::Udp { port } => udp::probe(target, SocketAddr::new(addr, *port)).await,
+        Probe::Tls { port } => tls::probe(target, SocketAddr::new(addr, *port)).await,
+        Probe::Http { url } => http::probe(target, url, addr, dns).await,
+        Probe::Dns {
+            port,
+            name,
+            record_type,
+        } => dns::query(target, SocketAddr::new(addr, *port), name, *record_type).await,
+    }
+}
+
+/// Sends the target's burst of probes to the address, spaced by the burst
+/// gap. The probes run concurrently, so a slow reply doesn't delay the next.
+async fn send_burst(
+    target: Arc<Target>,
+    icmp: Engine,
+    addr: IpAddr,
+    dns: Option<Duration>,
+) -> Vec<Result<Reply, Failure>> {
+    let mut gap = tokio::time::interval(target.burst_gap);
+    let mut probes = Vec::new();
+
+    for _ in 0..target.burst.get() {
+        gap.tick().await;
+
+        let (target, icmp) = (target.clone(), icmp.clone());
+        probes.push(tokio::spawn(
+            async move { send(&target, &icmp, addr, dns).await }.in_current_span(),
+        ));
+    }
+
+    let mut results = Vec::with_capacity(probes.len());
+
+    for probe in probes {
+        results.push(
+            probe
+                .await
+                .unwrap_or_else(|err| Err(Failure::new("internal", err))),
+        );
+    }
+
+    results
+}
+
+/// Formats the address a probe is sent to, including the port if it has one.
+fn endpoint(probe: &Probe, addr: IpAddr) -> String {
+    match probe {
+        Probe::Icmp => addr.to_string(),
+        Probe::Tcp { port } | Probe::Udp { port } | Probe::Tls { port } => {
+            SocketAddr::new(addr, *port).to_string()
+        }
+        Probe::Http { url } => {
+            SocketAddr::new(addr, url.port_or_known_default().unwrap_or(80)).to_string()
+        }
+        Probe::Dns { port, .. } => SocketAddr::new(addr, *port).to_string(),
+    }
+}
+
+/// Logs a burst where every probe succeeded.
+fn log_burst(level: ReplyLevel, endpoint: &str, summary: &Summary) {
+    match level {
+        ReplyLevel::Off => {}
+        ReplyLevel::Trace => trace!("Burst to {} {}", endpoint, summary),
+        ReplyLevel::Debug => debug!("Burst to {} {}", endpoint, summary),
+        ReplyLevel::Info => info!("Burst to {} {}", endpoint, summary),
+    }
+}
+
+fn log_reply(level: ReplyLevel, endpoint: &str, reply: &Reply) {
+    if level == ReplyLevel::Off {
+        return;
+    }
+
+    let mut details = format!("time={:.3} ms", reply.rtt.as_secs_f64() * 1000.0);
+
+    if let Some(detail) = &reply.detail {
+        details.push_str(", ");
+        details.push_str(detail);
+    }
+
+    for (phase, duration) in &reply.phases {
+        details.push_str(&format!(
+            ", {}={:.3} ms",
+            phase,
+            duration.as_secs_f64() * 1000.0
+        ));
+    }
+
+    match level {
+        ReplyLevel::Off => {}
+        ReplyLevel::Trace => trace!("Reply from {}, {}", endpoint, details),
+        ReplyLevel::Debug => debug!("Reply from {}, {}", endpoint, details),
+        ReplyLevel::Info => info!("Reply from {}, {}", endpoint, details),
+    }
+}
@@ -0,0 +1,241 @@
+use std::{net::IpAddr, time::Duration};
+
+use hickory_resolver::TokioResolver;
+use tokio::time::Instant;
+use tracing::{error, info, warn};
+
+use crate::config::{Family, Host};
+
+/// Shortest time a resolved address is used, protects against records with
+/// a zero or very small TTL.
+const MIN_RESOLVE_INTERVAL: Duration = Duration::from_secs(5);
+
+/// What [`Resolution::refresh`] did.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Refresh {
+    /// No lookup was due.
+    Cached,
+    /// The hostname was looked up, taking this long.
+    Resolved(Duration),
+    /// A lookup was due and failed.
+    Failed,
+}
+
+/// Keeps the addresses of a target up to date by re-resolving its hostname
+/// whenever the previous lookup expires.
+pub struct Resolution {
+    host: Host,
+    family: Family,
+    resolver: TokioResolver,
+    max_interval: Duration,
+    /// Known addresses, the one probed in single address mode comes first.
+    addresses: Vec<IpAddr>,
+    refresh_at: Instant,
+}
+
+impl Resolution {
+    pub fn new(
+        host: Host,
+        family: Family,
+        resolver: TokioResolver,
+        max_interval: Duration,
+    ) -> Self {
+        let addresses = match host {
+            Host::Ip(addr) => vec![addr],
+            Host::Name(_) => Vec::new(),
+        };
+
+        Self {
+            host,
+            family,
+            resolver,
+            max_interval,
+            addresses,
+            refresh_at: Instant::now(),
+        }
+    }
+
+    /// Returns the address to probe in single address mode.
+    pub fn address(&self) -> Option<IpAddr> {
+        self.addresses.first().copied()
+    }
+
+    /// Returns all known addresses of the target.
+    pub fn addresses(&self) -> &[IpAddr] {
+        &self.addresses
+    }
+
+    /// Resolves the hostname again if the last lookup expired. A failed lookup
+    /// keeps the previously known addresses and is retried after
+    /// [`MIN_RESOLVE_INTERVAL`], so a DNS outage doesn't stall every probe.
+    pub async fn refresh(&mut self) -> Refresh {
+        let Host::Name(name) = &self.host else {
+            return Refresh::Cached;
+        };
+
+        let now = Instant::now();
+
+        if now < self.refresh_at {
+            return Refresh::Cached;
+        }
+
+        let res = self.resolver.lookup_ip(name.as_str()).await;
+        let took = now.elapsed();
+
+        match res {
+            Ok(lookup) => {
+                let valid_until = Instant::from_std(lookup.valid_until());
+                self.resolved(now, lookup.iter().collect(), valid_until);
+
+                Refresh::Resolved(took)
+            }
+            Err(err) => {
+                error!("DNS resolution failed for {}, error: {}", self.host, err);
+                self.failed(now);
+
+                Refresh::Failed
+            }
+        }
+    }
+
+    /// Applies the result of a successful lookup made at `now`.
+    fn resolved(&mut self, now: Instant, addresses: Vec<IpAddr>, valid_until: Instant) {
+        let mut addresses = addresses
+            .into_iter()
+            .filter(|addr| self.family.matches(addr))
+            .collect::<Vec<_>>();
+
+        // stay on the current address while the host still has it,
+        // round-robin records would otherwise flip on every lookup
+        if let Some(current) = self.addresses.first()
+            && let Some(pos) = addresses.iter().position(|addr| addr == current)
+        {
+            addresses[..=pos].rotate_right(1);
+        }
+
+        if addresses.is_empty() {
+            warn!(
+                "DNS resolution returned no {} addresses for {}",
+                self.family, self.host
+            );
+        } else if self.addresses.is_empty() {
+            info!("Resolved {} to {}", self.host, format_addresses(&addresses));
+        } else if !same_addresses(&self.addresses, &addresses) {
+            info!(
+                "Addresses of {} changed from {} to {}",
+                self.host,
+                format_addresses(&self.addresses),
+                format_addresses(&addresses)
+            );
+        }
+
+        if !addresses.is_empty() {
+            self.addresses = addresses;
+        }
+
+        let ttl = valid_until.saturating_duration_since(now);
+        self.refresh_at = now + ttl.max(MIN_RESOLVE_INTERVAL).min(self.max_interval);
+    }
+
+    /// Schedules the retry of a lookup that failed at `now`.
+    fn failed(&mut self, now: Instant) {
+        self.refresh_at = now + MIN_RESOLVE_INTERVAL.min(self.max_interval);
+    }
+}
+
+fn same_addresses(left: &[IpAddr], right: &[IpAddr]) -> bool {
+    left.len() == right.len() && left.iter().all(|addr| right.contains(addr))
+}
+
+pub fn format_addresses(addresses: &[IpAddr]) -> String {
+    addresses
+        .iter()
+        .map(IpAddr::to_string)
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+#[cfg(test)]
+mod tests {
+    use hickory_resolver::{config::ResolverConfig, name_server::TokioConnectionProvider};
+
+    use super::*;
+
+    fn lookup_target(family: Family, max_interval: Duration) -> Resolution {
+        let resolver = TokioResolver::builder_with_config(
+            ResolverConfig::default(),
+            TokioConnectionProvider::default(),
+        )
+        .build();
+
+        Resolution::new(
+            Host::Name("example.com".to_string()),
+            family,
+            resolver,
+            max_interval,
+        )
+    }
+
+    fn ip(addr: &str) -> IpAddr {
+        addr.parse().unwrap()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn ttl_is_clamped_to_resolve_interval() {
+        let mut resolution = lookup_target(Family::Any, Duration::from_secs(60));
+        let now = Instant::now();
+
+        resolution.resolved(now, vec![ip("192.0.2.1")], now + Duration::from_secs(3600));
+        assert_eq!(resolution.refresh_at, now + Duration::from_secs(60));
+
+        resolution.resolved(now, vec![ip("192.0.2.1")], now + Duration::from_secs(30));
+        assert_eq!(resolution.refresh_at, now + Duration::from_secs(30));
+
+        resolution.resolved(now, vec![ip("192.0.2.1")], now);
+        assert_eq!(resolution.refresh_at, now + MIN_RESOLVE_INTERVAL);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn failures_back_off_for_minimum_interval() {
+        let mut resolution = lookup_target(Family::Any, Duration::from_secs(60));
+        let now = Instant::now();
+
+        resolution.failed(now);
+        assert_eq!(resolution.refresh_at, now + MIN_RESOLVE_INTERVAL);
+        assert_eq!(resolution.refresh().await, Refresh::Cached);
+
+        // a resolve interval below the minimum still bounds the backoff
+        let mut resolution = lookup_target(Family::Any, Duration::from_secs(2));
+        resolution.failed(now);
+        assert_eq!(resolution.refresh_at, now + Duration::from_secs(2));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn failures_keep_last_good_addresses() {
+        let mut resolution = lookup_target(Family::Ipv4, Duration::from_secs(60));
+        let now = Instant::now();
+
+        resolution.resolved(
+            now,
+            vec![ip("192.0.2.1"), ip("2001:db8::1"), ip("192.0.2.2")],
+            now + Duration::from_secs(60),
+        );
+        assert_eq!(resolution.addresses(), [ip("192.0.2.1"), ip("192.0.2.2")]);
+
+        resolution.failed(now);
+        assert_eq!(resolution.address(), Some(ip("192.0.2.1")));
+
+        // neither does a lookup without addresses of the wanted family
+        resolution.resolved(now, vec![ip("2001:db8::1")], now + Duration::from_secs(60));
+        assert_eq!(resolution.addresses(), [ip("192.0.2.1"), ip("192.0.2.2")]);
+
+        // the current address stays first when the records are reordered
+        resolution.resolved(
+            now,
+            vec![ip("192.0.2.3"), ip("192.0.2.2"), ip("192.0.2.1")],
+            now + Duration::from_secs(60),
+        );
+        assert_eq!(resolution.address(), Some(ip("192.0.2.1")));
+        assert_eq!(resolution.addresses().len(), 3);
+    }
+}
@@ -0,0 +1,264 @@
+use std::{fmt, time::Duration};
+
+use serde::Deserialize;
+use tokio::time::Instant;
+
+/// Health of a target derived from consecutive probe outcomes.
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum State {
+    Up,
+    /// Probes are failing but not enough to consider the target down, or only
+    /// some of its addresses reply.
+    Degraded,
+    Down,
+}
+
+impl fmt::Display for State {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            State::Up => f.write_str("up"),
+            State::Degraded => f.write_str("degraded"),
+            State::Down => f.write_str("down"),
+        }
+    }
+}
+
+/// Result of probing every address of a target once.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Outcome {
+    /// Every address replied.
+    Success,
+    /// Some addresses replied.
+    Partial,
+    /// No address replied.
+    Failure,
+}
+
+/// A change of the target state.
+#[derive(Clone, Debug)]
+pub struct Transition {
+    pub from: State,
+    pub to: State,
+    /// Time since the target was last up, zero when going from up.
+    pub duration: Duration,
+    /// Probes lost since the target was last up.
+    pub lost: u64,
+    /// Consecutive failed ticks at the time of the transition.
+    pub failures: u32,
+}
+
+/// Outage detection state machine of a single target.
+#[derive(Debug)]
+pub struct Tracker {
+    down_after: u32,
+    up_after: u32,
+    state: State,
+    /// Consecutive ticks without any reply.
+    failures: u32,
+    /// Consecutive ticks with at least one reply.
+    reachable: u32,
+    /// Consecutive ticks where every address replied.
+    successes: u32,
+    /// Start of the current outage or degradation.
+    since: Option<Instant>,
+    lost: u64,
+}
+
+impl Tracker {
+    pub fn new(down_after: u32, up_after: u32) -> Self {
+        Self {
+            down_after,
+            up_after,
+            state: State::Up,
+            failures: 0,
+            reachable: 0,
+            successes: 0,
+            since: None,
+            lost: 0,
+        }
+    }
+
+    /// Changes the thresholds, used when a target is reconfigured.
+    pub fn set_thresholds(&mut self, down_after: u32, up_after: u32) {
+        self.down_after = down_after;
+        self.up_after = up_after;
+    }
+
+    pub fn state(&self) -> State {
+        self.state
+    }
+
+    /// Records the outcome of a tick where `lost` probes got no reply and
+    /// returns the transition it caused, if any.
+    pub fn update(&mut self, outcome: Outcome, lost: u64) -> Option<Transition> {
+        let now = Instant::now();
+
+        match outcome {
+            Outcome::Failure => {
+                self.failures += 1;
+                self.reachable = 0;
+                self.successes = 0;
+            }
+            Outcome::Partial => {
+                self.failures = 0;
+                self.reachable += 1;
+                self.successes = 0;
+            }
+            Outcome::Success => {
+                self.failures = 0;
+                self.reachable += 1;
+                self.successes += 1;
+            }
+        }
+
+        if lost > 0 && self.since.is_none() {
+            self.since = Some(now);
+        }
+        self.lost += lost;
+
+        let next = match (self.state, outcome) {
+            (_, Outcome::Failure) if self.failures >= self.down_after => State::Down,
+            (State::Up, Outcome::Failure | Outcome::Partial) => State::Degraded,
+            (State::Down, _) if self.successes >= self.up_after => State::Up,
+            (State::Down, _) if self.reachable >= self.up_after => State::Degraded,
+            (State::Degraded, _) if self.successes >= self.up_after => State::Up,
+            (state, _) => state,
+        };
+
+        if next == self.state {
+            return None;
+        }
+
+        let transition = Transition {
+            from: self.state,
+            to: next,
+            duration: self.since.map_or(Duration::ZERO, |since| now - since),
+            lost: self.lost,
+            failures: self.failures,
+        };
+
+        self.state = next;
+
+        if next == State::Up {
+            self.since = None;
+            self.lost = 0;
+        }
+
+        Some(transition)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TICK: Duration = Duration::from_secs(1);
+    const FAILURE: (Outcome, u64) = (Outcome::Failure, 1);
+    const PARTIAL: (Outcome, u64) = (Outcome::Partial, 1);
+    const SUCCESS: (Outcome, u64) = (Outcome::Success, 0);
+
+    /// Feeds the outcomes one tick apart and returns the resulting states.
+    async fn run(tracker: &mut Tracker, outcomes: &[(Outcome, u64)]) -> Vec<Option<State>> {
+        let mut states = Vec::new();
+
+        for &(outcome, lost) in outcomes {
+            tokio::time::advance(TICK).await;
+            states.push(
+                tracker
+                    .update(outcome, lost)
+                    .map(|transition| transition.to),
+            );
+        }
+
+        states
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn goes_down_after_consecutive_failures() {
+        let mut tracker = Tracker::new(3, 2);
+
+        let states = run(&mut tracker, &[FAILURE, FAILURE, FAILURE, FAILURE]).await;
+
+        assert_eq!(
+            states,
+            [Some(State::Degraded), None, Some(State::Down), None]
+        );
+        assert_eq!(tracker.state(), State::Down);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn success_resets_failure_count() {
+        let mut tracker = Tracker::new(3, 2);
+
+        let states = run(&mut tracker, &[FAILURE, FAILURE, SUCCESS, FAILURE, FAILURE]).await;
+
+        assert_eq!(states, [Some(State::Degraded), None, None, None, None]);
+        assert_eq!(tracker.state(), State::Degraded);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn comes_up_after_consecutive_successes() {
+        let mut tracker = Tracker::new(1, 2);
+
+        let states = run(&mut tracker, &[FAILURE, SUCCESS, FAILURE, SUCCESS, SUCCESS]).await;
+
+        assert_eq!(
+            states,
+            [Some(State::Down), None, None, None, Some(State::Up)]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn partial_replies_after_outage_are_degraded() {
+        let mut tracker = Tracker::new(1, 2);
+
+        let states = run(&mut tracker, &[FAILURE, PARTIAL, PARTIAL, SUCCESS, SUCCESS]).await;
+
+        assert_eq!(
+            states,
+            [
+                Some(State::Down),
+                None,
+                Some(State::Degraded),
+                None,
+                Some(State::Up)
+            ]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn partial_replies_degrade() {
+        let mut tracker = Tracker::new(1, 1);
+
+        let states = run(&mut tracker, &[PARTIAL, PARTIAL, SUCCESS]).await;
+
+        assert_eq!(states, [Some(State::Degraded), None, Some(State::Up)]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn outage_is_measured_until_up() {
+        let mut tracker = Tracker::new(2, 1);
+
+        run(&mut tracker, &[FAILURE, FAILURE]).await;
+        tokio::time::advance(TICK).await;
+
+        // partial replies end the outage but not the lost count
+        let degraded = tracker.update(Outcome::Partial, 2).unwrap();
+        assert_eq!(degraded.to, State::Degraded);
+        assert_eq!(degraded.lost, 4);
+        assert_eq!(degraded.duration, 2 * TICK);
+
+        tokio::time::advance(TICK).await;
+        let up = tracker.update(Outcome::Success, 0).unwrap();
+        assert_eq!(up.to, State::Up);
+        assert_eq!(up.lost, 4);
+        assert_eq!(up.duration, 3 * TICK);
+
+        // the next outage starts from scratch
+        tokio::time::advance(TICK).await;
+        let degraded = tracker.update(Outcome::Failure, 1).unwrap();
+        assert_eq!(degraded.lost, 1);
+        assert_eq!(degraded.duration, Duration::ZERO);
+    }
+}
@@ -0,0 +1,490 @@
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    fmt,
+    net::IpAddr,
+    ops::AddAssign,
+    time::Duration,
+};
+
+use humantime::format_duration;
+use tokio::time::Instant;
+use tracing::info;
+
+use crate::config::{Address, Probe};
+
+/// Outcome of a single probe.
+#[derive(Clone, Copy, Debug)]
+struct Sample {
+    at: Instant,
+    /// Probed address, `None` if the target had no address to probe.
+    addr: Option<IpAddr>,
+    /// Round-trip time, `None` if the probe was lost.
+    rtt: Option<Duration>,
+}
+
+/// Probe statistics of a target, both since start and over rolling windows.
+#[derive(Debug)]
+pub struct Stats {
+    started: Instant,
+    /// Samples covering the longest rolling window.
+    samples: VecDeque<Sample>,
+    /// Anomalous replies covering the longest rolling window.
+    anomalies: VecDeque<(Instant, IpAddr, Anomalies)>,
+    retention: Duration,
+    total: Summary,
+    /// Statistics since start of every address probed.
+    addresses: BTreeMap<IpAddr, Summary>,
+    /// Last discovered path MTU of every address.
+    pmtu: BTreeMap<IpAddr, u32>,
+}
+
+impl Stats {
+    pub fn new(retention: Duration) -> Self {
+        Self {
+            started: Instant::now(),
+            samples: VecDeque::new(),
+            anomalies: VecDeque::new(),
+            retention,
+            total: Summary::default(),
+            addresses: BTreeMap::new(),
+            pmtu: BTreeMap::new(),
+        }
+    }
+
+    /// Changes how long samples are kept, used when the windows are reconfigured.
+    pub fn set_retention(&mut self, retention: Duration) {
+        self.retention = retention;
+    }
+
+    pub fn record(&mut self, addr: Option<IpAddr>, rtt: Option<Duration>) {
+        let now = Instant::now();
+
+        self.total.add(addr, rtt);
+        if let Some(addr) = addr {
+            self.addresses.entry(addr).or_default().add(Some(addr), rtt);
+        }
+        self.samples.push_back(Sample { at: now, addr, rtt });
+        self.expire(now);
+    }
+
+    /// Records replies from the address that arrived late, twice or out of
+    /// order since the last call.
+    pub fn record_anomalies(&mut self, addr: IpAddr, anomalies: Anomalies) {
+        let now = Instant::now();
+
+        self.total.anomalies += anomalies;
+        self.addresses.entry(addr).or_default().anomalies += anomalies;
+        self.anomalies.push_back((now, addr, anomalies));
+        self.expire(now);
+    }
+
+    fn expire(&mut self, now: Instant) {
+        while let Some(sample) = self.samples.front()
+            && now.duration_since(sample.at) > self.retention
+        {
+            self.samples.pop_front();
+        }
+
+        while let Some((at, ..)) = self.anomalies.front()
+            && now.duration_since(*at) > self.retention
+        {
+            self.anomalies.pop_front();
+        }
+    }
+
+    /// Statistics since the target started being probed.
+    pub fn total(&self) -> &Summary {
+        &self.total
+    }
+
+    /// Statistics since start of every address probed.
+    pub fn total_by_address(&self) -> &BTreeMap<IpAddr, Summary> {
+        &self.addresses
+    }
+
+    /// Stores the discovered path MTU of the address and returns the
+    /// previous one.
+    pub fn set_pmtu(&mut self, addr: IpAddr, mtu: u32) -> Option<u32> {
+        self.pmtu.insert(addr, mtu)
+    }
+
+    /// Last discovered path MTU of every address.
+    pub fn pmtu(&self) -> &BTreeMap<IpAddr, u32> {
+        &self.pmtu
+    }
+
+    /// Time since the target started being probed.
+    pub fn elapsed(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    /// Statistics of the samples recorded within the last `window`.
+    pub fn window(&self, window: Duration) -> Summary {
+        let now = Instant::now();
+        let mut summary = Summary::default();
+
+        for sample in &self.samples {
+            if now.duration_since(sample.at) <= window {
+                summary.add(sample.addr, sample.rtt);
+            }
+        }
+
+        for (at, _, anomalies) in &self.anomalies {
+            if now.duration_since(*at) <= window {
+                summary.anomalies += *anomalies;
+            }
+        }
+
+        summary
+    }
+
+    /// Statistics of every address probed within the last `window`.
+    pub fn window_by_address(&self, window: Duration) -> BTreeMap<IpAddr, Summary> {
+        let now = Instant::now();
+        let mut summaries = BTreeMap::<IpAddr, Summary>::new();
+
+        for sample in &self.samples {
+            if let Some(addr) = sample.addr
+                && now.duration_since(sample.at) <= window
+            {
+                summaries
+                    .entry(addr)
+                    .or_default()
+                    .add(Some(addr), sample.rtt);
+            }
+        }
+
+        for (at, addr, anomalies) in &self.anomalies {
+            if now.duration_since(*at) <= window {
+                summaries.entry(*addr).or_default().anomalies += *anomalies;
+            }
+        }
+
+        summaries
+    }
+}
+
+/// Echo replies that didn't answer an outstanding request in order. Late
+/// replies arrived after the request timed out, they fail the probe but
+/// don't count as lost.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Anomalies {
+    pub late: u64,
+    pub duplicates: u64,
+    pub reordered: u64,
+}
+
+impl Anomalies {
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+impl AddAssign for Anomalies {
+    fn add_assign(&mut self, other: Self) {
+        self.late += other.late;
+        self.duplicates += other.duplicates;
+        self.reordered += other.reordered;
+    }
+}
+
+impl fmt::Display for Anomalies {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let counts = [
+            (self.late, "late"),
+            (self.duplicates, "duplicate"),
+            (self.reordered, "reordered"),
+        ]
+        .into_iter()
+        .filter(|(count, _)| *count > 0)
+        .map(|(count, kind)| format!("{count} {kind}"))
+        .collect::<Vec<_>>();
+
+        write!(f, "{} replies", counts.join(", "))
+    }
+}
+
+/// Aggregated packet loss, RTT and jitter over a set of samples.
+#[derive(Clone, Debug, Default)]
+pub struct Summary {
+    pub sent: u64,
+    pub received: u64,
+    min: Option<Duration>,
+    max: Option<Duration>,
+    /// Sum of RTTs in milliseconds.
+    sum: f64,
+    /// Sum of squared RTTs in milliseconds, for the standard deviation.
+    sum_sq: f64,
+    /// Last RTT of every address, jitter is only computed between replies
+    /// from the same address as the paths to different addresses differ.
+    last_rtt: HashMap<Option<IpAddr>, Duration>,
+    /// Interarrival jitter in milliseconds, estimated as in RFC 3550.
+    jitter: f64,
+    pub anomalies: Anomalies,
+}
+
+impl Summary {
+    pub fn add(&mut self, addr: Option<IpAddr>, rtt: Option<Duration>) {
+        self.sent += 1;
+
+        let Some(rtt) = rtt else {
+            return;
+        };
+
+        self.received += 1;
+        self.min = Some(self.min.map_or(rtt, |min| min.min(rtt)));
+        self.max = Some(self.max.map_or(rtt, |max| max.max(rtt)));
+
+        let rtt_ms = as_millis(rtt);
+        self.sum += rtt_ms;
+        self.sum_sq += rtt_ms * rtt_ms;
+
+        if let Some(last_rtt) = self.last_rtt.insert(addr, rtt) {
+            let diff = (rtt_ms - as_millis(last_rtt)).abs();
+            self.jitter += (diff - self.jitter) / 16.0;
+        }
+    }
+
+    /// Share of the probes that got no reply at all, late replies arrived
+    /// and aren't lost.
+    pub fn loss_percent(&self) -> f64 {
+        if self.sent == 0 {
+            return 0.0;
+        }
+
+        let lost = (self.sent - self.received).saturating_sub(self.anomalies.late);
+
+        lost as f64 * 100.0 / self.sent as f64
+    }
+
+    /// Returns min/avg/max/mdev of the RTT in milliseconds, `None` if nothing
+    /// was received.
+    pub fn rtt(&self) -> Option<(f64, f64, f64, f64)> {
+        let (min, max) = self.min.zip(self.max)?;
+
+        let count = self.received as f64;
+        let avg = self.sum / count;
+        let mdev = (self.sum_sq / count - avg * avg).max(0.0).sqrt();
+
+        Some((as_millis(min), avg, as_millis(max), mdev))
+    }
+
+    pub fn jitter(&self) -> f64 {
+        self.jitter
+    }
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}/{} received, {:.1}% loss",
+            self.received,
+            self.sent,
+            self.loss_percent()
+        )?;
+
+        if !self.anomalies.is_empty() {
+            write!(f, ", {}", self.anomalies)?;
+        }
+
+        if let Some((min, avg, max, mdev)) = self.rtt() {
+            write!(
+                f,
+                ", rtt min/avg/max/mdev {:.3}/{:.3}/{:.3}/{:.3} ms, jitter {:.3} ms",
+                min, avg, max, mdev, self.jitter
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+fn as_millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+/// Logs a single line with the statistics of every window. Targets probed on
+/// several addresses get an additional line per address.
+pub fn log_summary(stats: &Stats, windows: &[Duration]) {
+    let summary = windows
+        .iter()
+        .map(|&window| format!("{}: {}", format_duration(window), stats.window(window)))
+        .collect::<Vec<_>>()
+        .join("; ");
+
+    info!("Statistics {}", summary);
+
+    if !stats.pmtu().is_empty() {
+        info!("Path MTU {}", format_pmtu(stats.pmtu()));
+    }
+
+    let longest = windows.iter().copied().max().unwrap_or_default();
+    let addresses = stats.window_by_address(longest);
+
+    if addresses.len() < 2 {
+        return;
+    }
+
+    let by_window = windows
+        .iter()
+        .map(|&window| (window, stats.window_by_address(window)))
+        .collect::<Vec<_>>();
+
+    for addr in addresses.keys() {
+        let summary = by_window
+            .iter()
+            .map(|(window, summaries)| {
+                format!(
+                    "{}: {}",
+                    format_duration(*window),
+                    summaries.get(addr).cloned().unwrap_or_default()
+                )
+            })
+            .collect::<Vec<_>>()
+            .join("; ");
+
+        info!("Statistics of {} {}", addr, summary);
+    }
+}
+
+fn format_pmtu(pmtu: &BTreeMap<IpAddr, u32>) -> String {
+    pmtu.iter()
+        .map(|(addr, mtu)| format!("{mtu} bytes to {addr}"))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Logs the statistics since start in the format of `ping`.
+pub fn log_final_summary(address: &Address, stats: &Stats) {
+    let total = stats.total();
+    let elapsed = Duration::from_secs(stats.elapsed().as_secs());
+
+    if address.probe == Probe::Icmp {
+        info!("--- {} ping statistics ---", address);
+        info!(
+            "{} packets transmitted, {} received, {:.2}% packet loss, time {}",
+            total.sent,
+            total.received,
+            total.loss_percent(),
+            format_duration(elapsed)
+        );
+    } else {
+        info!("--- {} probe statistics ---", address);
+        info!(
+            "{} probes sent, {} succeeded, {:.2}% failed, time {}",
+            total.sent,
+            total.received,
+            total.loss_percent(),
+            format_duration(elapsed)
+        );
+    }
+
+    if !total.anomalies.is_empty() {
+        info!("{}", total.anomalies);
+    }
+
+    if let Some((min, avg, max, mdev)) = total.rtt() {
+        info!(
+            "rtt min/avg/max/mdev = {:.3}/{:.3}/{:.3}/{:.3} ms, jitter {:.3} ms",
+            min,
+            avg,
+            max,
+            mdev,
+            total.jitter()
+        );
+    }
+
+    if !stats.pmtu().is_empty() {
+        info!("path MTU {}", format_pmtu(stats.pmtu()));
+    }
+
+    let addresses = stats.total_by_address();
+
+    if addresses.len() > 1 {
+        for (addr, summary) in addresses {
+            info!("{}: {}", addr, summary);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const V4: IpAddr = IpAddr::V4(std::net::Ipv4Addr::new(192, 0, 2, 1));
+    const V6: IpAddr = IpAddr::V6(std::net::Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1));
+
+    fn ms(value: u64) -> Option<Duration> {
+        Some(Duration::from_millis(value))
+    }
+
+    #[test]
+    fn jitter_ignores_rtt_gap_between_addresses() {
+        let mut stats = Stats::new(Duration::from_secs(60));
+
+        for _ in 0..10 {
+            stats.record(Some(V4), ms(10));
+            stats.record(Some(V6), ms(50));
+        }
+
+        assert_eq!(stats.total().jitter(), 0.0);
+        assert_eq!(stats.window(Duration::from_secs(60)).jitter(), 0.0);
+    }
+
+    #[test]
+    fn addresses_are_summarized_separately() {
+        let mut stats = Stats::new(Duration::from_secs(60));
+
+        stats.record(Some(V4), ms(10));
+        stats.record(Some(V6), None);
+        stats.record(Some(V4), ms(20));
+        stats.record(Some(V6), None);
+        stats.record(None, None);
+
+        let total = stats.total();
+        assert_eq!((total.sent, total.received), (5, 2));
+
+        let v4 = &stats.total_by_address()[&V4];
+        assert_eq!((v4.sent, v4.received), (2, 2));
+        assert_eq!(v4.jitter(), 10.0 / 16.0);
+
+        let window = stats.window_by_address(Duration::from_secs(60));
+        assert_eq!(window.len(), 2);
+        assert_eq!(window[&V6].loss_percent(), 100.0);
+    }
+
+    #[test]
+    fn anomalies_are_summarized() {
+        let mut stats = Stats::new(Duration::from_secs(60));
+        let late = Anomalies {
+            late: 2,
+            ..Anomalies::default()
+        };
+
+        for _ in 0..3 {
+            stats.record(Some(V4), None);
+        }
+        assert_eq!(stats.total().loss_percent(), 100.0);
+        stats.record_anomalies(V4, late);
+        stats.record_anomalies(
+            V4,
+            Anomalies {
+                duplicates: 1,
+                ..Anomalies::default()
+            },
+        );
+
+        let window = stats.window(Duration::from_secs(60));
+        assert_eq!(window.anomalies, stats.total().anomalies);
+        assert_eq!(
+            window.to_string(),
+            "0/3 received, 33.3% loss, 2 late, 1 duplicate replies"
+        );
+        assert_eq!(
+            stats.window_by_address(Duration::from_secs(60))[&V4].anomalies,
+            stats.total_by_address()[&V4].anomalies
+        );
+        assert_eq!(late.to_string(), "2 late replies");
+    }
+}
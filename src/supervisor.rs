@@ -0,0 +1,311 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use hickory_resolver::TokioResolver;
+use humantime::format_duration;
+use tokio::task::{AbortHandle, JoinSet};
+use tracing::{Instrument, Span, error, info, info_span};
+
+use crate::{
+    alert::Alerts,
+    config::{Address, Target},
+    fault::Health,
+    hook::HookRunner,
+    icmp::Engine,
+    metrics::{Metrics, TargetKey},
+    probe::probe,
+    state::Tracker,
+    stats::{Stats, log_final_summary},
+};
+
+/// Time before the probe of a target that panicked is started again.
+const RESTART_DELAY: Duration = Duration::from_secs(5);
+
+/// Services shared by all probes.
+#[derive(Clone)]
+pub struct Context {
+    pub resolver: TokioResolver,
+    pub metrics: Arc<Metrics>,
+    pub alerts: Alerts,
+    pub hooks: HookRunner,
+    pub icmp: Engine,
+    pub health: Health,
+}
+
+/// Keeps one probe task running per configured target.
+pub struct Supervisor {
+    context: Context,
+    tasks: JoinSet<()>,
+    running: HashMap<Target, Running>,
+}
+
+struct Running {
+    handle: AbortHandle,
+    history: History,
+}
+
+/// State of a target that outlives its probe task, so a target restarted
+/// with new settings keeps its statistics and an ongoing outage.
+#[derive(Clone)]
+struct History {
+    stats: Arc<Mutex<Stats>>,
+    tracker: Arc<Mutex<Tracker>>,
+}
+
+/// Outcome of applying a new target list.
+#[derive(Debug, Default)]
+pub struct UpdateSummary {
+    pub started: usize,
+    pub stopped: usize,
+    pub unchanged: usize,
+}
+
+impl Supervisor {
+    pub fn new(context: Context) -> Self {
+        Self {
+            context,
+            tasks: JoinSet::new(),
+            running: HashMap::new(),
+        }
+    }
+
+    /// Starts probes for new targets and stops probes for targets that are no
+    /// longer configured. Probes of unchanged targets keep running untouched,
+    /// restarted targets with the same host and label keep their statistics
+    /// and outage state.
+    pub fn update(&mut self, targets: Vec<Target>) -> UpdateSummary {
+        let mut summary = UpdateSummary::default();
+        let mut stopped = HashMap::<(Address, Option<String>), History>::new();
+
+        self.running.retain(|target, running| {
+            if targets.contains(target) {
+                return true;
+            }
+
+            running.handle.abort();
+            target_span(target).in_scope(|| info!("Stopped probing {}", target.address));
+            stopped.insert(
+                (target.address.clone(), target.label.clone()),
+                running.history.clone(),
+            );
+            summary.stopped += 1;
+
+            false
+        });
+
+        for target in targets {
+            if self.running.contains_key(&target) {
+                summary.unchanged += 1;
+                continue;
+            }
+
+            let retention = target.stats.retention();
+            let (down_after, up_after) = (target.down_after.get(), target.up_after.get());
+            let history = match stopped.remove(&(target.address.clone(), target.label.clone())) {
+                Some(history) => {
+                    history.stats.lock().unwrap().set_retention(retention);
+                    history
+                        .tracker
+                        .lock()
+                        .unwrap()
+                        .set_thresholds(down_after, up_after);
+                    history
+                }
+                None => History {
+                    stats: Arc::new(Mutex::new(Stats::new(retention))),
+                    tracker: Arc::new(Mutex::new(Tracker::new(down_after, up_after))),
+                },
+            };
+
+            let handle = spawn(
+                &mut self.tasks,
+                &self.context,
+                &target,
+                &history,
+                Duration::ZERO,
+            );
+
+            self.running.insert(target, Running { handle, history });
+            summary.started += 1;
+        }
+
+        for (address, label) in stopped.into_keys() {
+            let key = TargetKey::from_parts(&address, label.as_deref());
+            self.context.metrics.remove(&key);
+            self.context.health.remove(&key);
+        }
+
+        summary
+    }
+
+    /// Stops all probes and logs their final statistics.
+    pub fn shutdown(&mut self) {
+        for (target, running) in self.running.drain() {
+            running.handle.abort();
+
+            let stats = running.history.stats.lock().unwrap();
+            target_span(&target).in_scope(|| log_final_summary(&target.address, &stats));
+        }
+    }
+
+    /// Waits for the next probe to fail, which only a panic makes it do, and
+    /// starts it again after [`RESTART_DELAY`] with the same history. The
+    /// other targets keep being probed. Stopped probes are skipped.
+    ///
+    /// Returns `None` when no probes are running.
+    pub async fn restart_failed(&mut self) -> Option<()> {
+        loop {
+            let err = match self.tasks.join_next().await? {
+                Err(err) if !err.is_cancelled() => err,
+                _ => continue,
+            };
+
+            let Some((target, running)) = self
+                .running
+                .iter_mut()
+                .find(|(_, running)| running.handle.id() == err.id())
+            else {
+                continue;
+            };
+
+            target_span(target).in_scope(|| {
+                error!(
+                    "Probing {} failed, restarting in {}, error: {}",
+                    target.address,
+                    format_duration(RESTART_DELAY),
+                    err
+                )
+            });
+
+            // the panic may have happened while the history was locked
+            running.history.stats.clear_poison();
+            running.history.tracker.clear_poison();
+            running.handle = spawn(
+                &mut self.tasks,
+                &self.context,
+                target,
+                &running.history,
+                RESTART_DELAY,
+            );
+
+            return Some(());
+        }
+    }
+}
+
+/// Spawns the probe of the target, starting after `delay`.
+fn spawn(
+    tasks: &mut JoinSet<()>,
+    context: &Context,
+    target: &Target,
+    history: &History,
+    delay: Duration,
+) -> AbortHandle {
+    let probe = probe(
+        Arc::new(target.clone()),
+        history.stats.clone(),
+        history.tracker.clone(),
+        context.clone(),
+    );
+
+    tasks.spawn(
+        async move {
+            tokio::time::sleep(delay).await;
+            probe.await;
+        }
+        .instrument(target_span(target)),
+    )
+}
+
+fn target_span(target: &Target) -> Span {
+    info_span!("target", host = %target.address, label = target.label.as_deref())
+}
+
+#[cfg(test)]
+mod tests {
+    use hickory_resolver::{config::ResolverConfig, name_server::TokioConnectionProvider};
+
+    use super::*;
+    use crate::{Args, config};
+
+    fn load(addresses: &str) -> config::Config {
+        let args = ["pinger", "-a", addresses, "-i", "1h", "--no-gateway"];
+
+        config::load(&<Args as clap::Parser>::try_parse_from(args).unwrap(), None).unwrap()
+    }
+
+    fn supervisor(config: &config::Config) -> Supervisor {
+        let resolver = TokioResolver::builder_with_config(
+            ResolverConfig::default(),
+            TokioConnectionProvider::default(),
+        )
+        .build();
+
+        Supervisor::new(Context {
+            resolver,
+            metrics: Arc::default(),
+            alerts: Alerts::default(),
+            hooks: HookRunner::new(&config.hooks),
+            icmp: Engine::default(),
+            health: Health::default(),
+        })
+    }
+
+    fn history<'a>(supervisor: &'a Supervisor, target: &Target) -> &'a History {
+        &supervisor.running[target].history
+    }
+
+    #[tokio::test]
+    async fn update_restarts_changed_targets_only() {
+        let config = load("tcp://127.0.0.1:9,tcp://127.0.0.1:10,tcp://127.0.0.1:11");
+        let [kept, changed, removed] = <[Target; 3]>::try_from(config.targets.clone()).unwrap();
+        let mut supervisor = supervisor(&config);
+        let metrics = supervisor.context.metrics.clone();
+
+        let summary = supervisor.update(config.targets.clone());
+        assert_eq!(
+            (summary.started, summary.stopped, summary.unchanged),
+            (3, 0, 0)
+        );
+
+        let kept_history = history(&supervisor, &kept).clone();
+        let kept_handle = supervisor.running[&kept].handle.id();
+        let changed_history = history(&supervisor, &changed).clone();
+        let removed_key = TargetKey::new(&removed);
+        metrics.record_failure(&removed_key, "refused");
+        assert!(metrics.render().contains(&removed_key.to_string()));
+
+        let changed = Target {
+            interval: Duration::from_secs(7200),
+            ..changed
+        };
+        let added = Target {
+            label: Some("added".to_string()),
+            ..removed.clone()
+        };
+
+        let summary = supervisor.update(vec![kept.clone(), changed.clone(), added.clone()]);
+        assert_eq!(
+            (summary.started, summary.stopped, summary.unchanged),
+            (2, 2, 1)
+        );
+
+        // unchanged targets keep running, changed ones keep their history
+        assert_eq!(supervisor.running[&kept].handle.id(), kept_handle);
+        assert!(Arc::ptr_eq(
+            &history(&supervisor, &kept).stats,
+            &kept_history.stats
+        ));
+        let history = history(&supervisor, &changed);
+        assert!(Arc::ptr_eq(&history.stats, &changed_history.stats));
+        assert!(Arc::ptr_eq(&history.tracker, &changed_history.tracker));
+
+        // a new label is a new target, the removed one is forgotten
+        assert!(supervisor.running.contains_key(&added));
+        assert!(!supervisor.running.contains_key(&removed));
+        assert!(!metrics.render().contains(&removed_key.to_string()));
+    }
+}
@@ -0,0 +1,51 @@
+use std::{
+    io::{self, ErrorKind},
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::{
+    net::{TcpSocket, TcpStream},
+    time::timeout,
+};
+
+use crate::{bind, config::Binding, probe::Failure};
+
+/// Opens a TCP connection and returns how long the handshake took. The
+/// connection is closed right away.
+pub async fn connect(
+    addr: SocketAddr,
+    binding: &Binding,
+    limit: Duration,
+) -> Result<Duration, Failure> {
+    let start = Instant::now();
+
+    match timeout(limit, open(addr, binding)).await {
+        Ok(Ok(_)) => Ok(start.elapsed()),
+        Ok(Err(err)) => Err(Failure::new(error_kind(&err), err)),
+        Err(_) => Err(Failure::new("timeout", "connection timed out")),
+    }
+}
+
+/// Connects to the address from a socket with the binding applied.
+pub async fn open(addr: SocketAddr, binding: &Binding) -> io::Result<TcpStream> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    bind::apply(&socket, binding)?;
+    socket.set_nonblocking(true)?;
+
+    TcpSocket::from_std_stream(socket.into())
+        .connect(addr)
+        .await
+}
+
+/// Classifies a connection error for the failure metrics.
+pub fn error_kind(err: &io::Error) -> &'static str {
+    match err.kind() {
+        ErrorKind::ConnectionRefused => "refused",
+        ErrorKind::TimedOut => "timeout",
+        ErrorKind::HostUnreachable | ErrorKind::NetworkUnreachable => "unreachable",
+        ErrorKind::PermissionDenied => "permission",
+        _ => "io",
+    }
+}
@@ -0,0 +1,289 @@
+use std::{
+    io,
+    net::SocketAddr,
+    sync::{Arc, OnceLock},
+    time::{Instant, SystemTime},
+};
+
+use humantime::parse_rfc3339;
+use tokio::{net::TcpStream, time::timeout};
+use tokio_rustls::{
+    TlsConnector,
+    client::TlsStream,
+    rustls::{
+        self, CertificateError, ClientConfig, RootCertStore, crypto::ring, pki_types::ServerName,
+    },
+};
+
+use crate::{
+    config::Target,
+    probe::{Failure, Reply},
+    tcp,
+};
+
+const SECS_PER_DAY: u64 = 24 * 60 * 60;
+
+/// Shared TLS client configuration trusting the Mozilla root certificates.
+fn client_config() -> Arc<ClientConfig> {
+    static CONFIG: OnceLock<Arc<ClientConfig>> = OnceLock::new();
+
+    CONFIG
+        .get_or_init(|| {
+            let roots = RootCertStore {
+                roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+            };
+
+            let config = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+                .with_safe_default_protocol_versions()
+                .expect("ring supports the default protocol versions")
+                .with_root_certificates(roots)
+                .with_no_client_auth();
+
+            Arc::new(config)
+        })
+        .clone()
+}
+
+/// Performs the TLS handshake, verifying the certificate chain and that the
+/// certificate is valid for `host`.
+pub async fn handshake(stream: TcpStream, host: &str) -> Result<TlsStream<TcpStream>, Failure> {
+    let server_name =
+        ServerName::try_from(host.to_string()).map_err(|err| Failure::new("tls", err))?;
+
+    TlsConnector::from(client_config())
+        .connect(server_name, stream)
+        .await
+        .map_err(|err| {
+            Failure::new(
+                error_kind(&err),
+                format!("TLS handshake with {host} failed: {err}"),
+            )
+        })
+}
+
+/// Classifies a handshake error, certificate problems get their own kinds.
+fn error_kind(err: &io::Error) -> &'static str {
+    let Some(rustls::Error::InvalidCertificate(err)) = err
+        .get_ref()
+        .and_then(|err| err.downcast_ref::<rustls::Error>())
+    else {
+        return "tls";
+    };
+
+    match err {
+        CertificateError::NotValidForName | CertificateError::NotValidForNameContext { .. } => {
+            "san"
+        }
+        CertificateError::Expired
+        | CertificateError::ExpiredContext { .. }
+        | CertificateError::NotValidYet
+        | CertificateError::NotValidYetContext { .. } => "validity",
+        _ => "chain",
+    }
+}
+
+/// Completes a TLS handshake with the address and warns when the certificate
+/// expires within the configured period. The connection is closed right away.
+pub async fn probe(target: &Target, addr: SocketAddr) -> Result<Reply, Failure> {
+    let host = target.address.host.to_string();
+
+    let (connect, tls, remaining) = timeout(target.timeout, async {
+        let start = Instant::now();
+        let stream = tcp::open(addr, &target.binding)
+            .await
+            .map_err(|err| Failure::new(tcp::error_kind(&err), err))?;
+        let connect = start.elapsed();
+
+        let stream = handshake(stream, &host).await?;
+        let tls = start.elapsed() - connect;
+
+        let not_after = stream
+            .get_ref()
+            .1
+            .peer_certificates()
+            .and_then(|certs| certs.first())
+            .and_then(|cert| not_after(cert))
+            .ok_or_else(|| Failure::new("tls", "failed to read the certificate validity"))?;
+
+        let remaining = not_after
+            .duration_since(SystemTime::now())
+            .unwrap_or_default();
+
+        Ok((connect, tls, remaining))
+    })
+    .await
+    .map_err(|_| Failure::new("timeout", "handshake timed out"))??;
+
+    let days = remaining.as_secs() / SECS_PER_DAY;
+    let detail = format!("certificate expires in {days} days");
+
+    // an expired certificate already fails the handshake, one that is merely
+    // close to expiry still works and only degrades the target
+    let warning = (remaining < target.cert_expiry).then(|| detail.clone());
+
+    Ok(Reply {
+        rtt: connect + tls,
+        detail: Some(detail),
+        warning,
+        phases: vec![("connect", connect), ("tls", tls)],
+    })
+}
+
+/// Returns the end of the validity period of a DER encoded X.509 certificate.
+fn not_after(cert: &[u8]) -> Option<SystemTime> {
+    let (_, cert, _) = read_der(cert)?;
+    let (_, tbs, _) = read_der(cert)?;
+
+    // the version is optional and explicitly tagged
+    let mut fields = tbs;
+    let (tag, _, rest) = read_der(fields)?;
+    if tag == 0xa0 {
+        fields = rest;
+    }
+
+    // skip the serial number, signature algorithm and issuer
+    for _ in 0..3 {
+        fields = read_der(fields)?.2;
+    }
+
+    let (_, validity, _) = read_der(fields)?;
+    let (_, _, validity) = read_der(validity)?;
+    let (tag, time, _) = read_der(validity)?;
+
+    parse_time(tag, time)
+}
+
+/// Splits a DER element into its tag, contents and the bytes following it.
+fn read_der(input: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let (&tag, rest) = input.split_first()?;
+    let (&first, rest) = rest.split_first()?;
+
+    let (len, rest) = if first < 0x80 {
+        (usize::from(first), rest)
+    } else {
+        let count = usize::from(first & 0x7f);
+
+        if count == 0 || count > size_of::<u32>() || rest.len() < count {
+            return None;
+        }
+
+        let (len, rest) = rest.split_at(count);
+        let len = len
+            .iter()
+            .fold(0, |len, &byte| (len << 8) | usize::from(byte));
+
+        (len, rest)
+    };
+
+    if rest.len() < len {
+        return None;
+    }
+
+    let (contents, rest) = rest.split_at(len);
+
+    Some((tag, contents, rest))
+}
+
+/// Parses an ASN.1 UTCTime or GeneralizedTime in the UTC form certificates use.
+fn parse_time(tag: u8, time: &[u8]) -> Option<SystemTime> {
+    let time = std::str::from_utf8(time).ok()?.strip_suffix('Z')?;
+
+    if !time.bytes().all(|byte| byte.is_ascii_digit()) {
+        return None;
+    }
+
+    let (year, rest) = match (tag, time.len()) {
+        // UTCTime years 50 to 99 are in the 20th century
+        (0x17, 12) => {
+            let year = time[..2].parse::<u32>().ok()?;
+            (if year < 50 { 2000 + year } else { 1900 + year }, &time[2..])
+        }
+        (0x18, 14) => (time[..4].parse().ok()?, &time[4..]),
+        _ => return None,
+    };
+
+    parse_rfc3339(&format!(
+        "{year:04}-{}-{}T{}:{}:{}Z",
+        &rest[..2],
+        &rest[2..4],
+        &rest[4..6],
+        &rest[6..8],
+        &rest[8..10]
+    ))
+    .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn der(tag: u8, contents: &[u8]) -> Vec<u8> {
+        let mut out = vec![tag];
+
+        if contents.len() < 0x80 {
+            out.push(contents.len() as u8);
+        } else {
+            out.push(0x82);
+            out.extend_from_slice(&(contents.len() as u16).to_be_bytes());
+        }
+
+        out.extend_from_slice(contents);
+        out
+    }
+
+    fn certificate(version: bool, not_after: Vec<u8>) -> Vec<u8> {
+        let mut tbs = Vec::new();
+        if version {
+            tbs.extend(der(0xa0, &der(0x02, &[2])));
+        }
+        tbs.extend(der(0x02, &[1]));
+        tbs.extend(der(0x30, &[]));
+        // a long issuer exercises the long length form
+        tbs.extend(der(0x30, &[0; 200]));
+        tbs.extend(der(
+            0x30,
+            &[der(0x17, b"240101000000Z"), not_after].concat(),
+        ));
+        tbs.extend(der(0x30, &[]));
+
+        der(0x30, &[der(0x30, &tbs), der(0x30, &[]), der(0x03, &[0])].concat())
+    }
+
+    #[test]
+    fn reads_utc_time() {
+        let cert = certificate(true, der(0x17, b"350615123000Z"));
+
+        assert_eq!(
+            not_after(&cert),
+            Some(parse_rfc3339("2035-06-15T12:30:00Z").unwrap())
+        );
+    }
+
+    #[test]
+    fn reads_generalized_time() {
+        let cert = certificate(false, der(0x18, b"20510102030405Z"));
+
+        assert_eq!(
+            not_after(&cert),
+            Some(parse_rfc3339("2051-01-02T03:04:05Z").unwrap())
+        );
+    }
+
+    #[test]
+    fn utc_time_before_2000() {
+        assert_eq!(
+            parse_time(0x17, b"991231235959Z"),
+            Some(parse_rfc3339("1999-12-31T23:59:59Z").unwrap())
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_certificates() {
+        let cert = certificate(true, der(0x17, b"350615123000Z"));
+
+        assert_eq!(not_after(&cert[..cert.len() / 2]), None);
+        assert_eq!(not_after(&[0x30, 0x84, 0xff]), None);
+        assert_eq!(parse_time(0x17, b"3506151230Z"), None);
+        assert_eq!(parse_time(0x18, b"2035O615123000Z"), None);
+    }
+}
@@ -0,0 +1,395 @@
+use std::{fmt, net::IpAddr, num::NonZeroU8, sync::Arc, time::Duration};
+
+use tokio::{task::JoinSet, time::MissedTickBehavior};
+use tracing::Instrument;
+
+use crate::{
+    config::Target,
+    icmp::{Engine, Response},
+    probe::Failure,
+    stats::Summary,
+};
+
+/// Highest TTL probed, the default of `traceroute`.
+pub const MAX_HOPS: u8 = 30;
+/// Probes sent to every hop of a snapshot.
+const ROUNDS: usize = 3;
+/// Time between the requests of a round with consecutive TTLs.
+const STAGGER: Duration = Duration::from_millis(10);
+
+/// Responses of the routers at one distance on the path.
+#[derive(Clone, Debug, Default)]
+pub struct Hop {
+    /// Routers that answered, several if the path is load balanced.
+    pub responders: Vec<IpAddr>,
+    pub summary: Summary,
+}
+
+impl Hop {
+    pub fn add(&mut self, res: &Result<Response, Failure>) {
+        match res {
+            Ok(response) => {
+                if !self.responders.contains(&response.from) {
+                    self.responders.push(response.from);
+                }
+
+                self.summary.add(Some(response.from), Some(response.rtt));
+            }
+            Err(_) => self.summary.add(None, None),
+        }
+    }
+}
+
+impl fmt::Display for Hop {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.responders.is_empty() {
+            return write!(f, "*: {}", self.summary);
+        }
+
+        let responders = self
+            .responders
+            .iter()
+            .map(IpAddr::to_string)
+            .collect::<Vec<_>>()
+            .join(", ");
+
+        write!(f, "{}: {}", responders, self.summary)
+    }
+}
+
+/// Sends one echo request per TTL up to `max_hops` and returns the responses
+/// ordered by TTL. The requests go out in order a little apart, so routers
+/// limiting the rate of their errors see them one at a time, and stop at the
+/// first hop that ends the path. Only timeouts count as lost, other failures
+/// like a missing permission abort the round.
+pub async fn round(
+    icmp: &Engine,
+    target: &Target,
+    addr: IpAddr,
+    max_hops: u8,
+) -> Result<Vec<Result<Response, Failure>>, Failure> {
+    let mut probes = JoinSet::new();
+    let mut handles = Vec::new();
+    let mut stagger = tokio::time::interval(STAGGER);
+    stagger.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    let mut responses = (1..=max_hops)
+        .map(|_| Err(Failure::new("timeout", "no reply received")))
+        .collect::<Vec<_>>();
+    // the closest hop that answered the path ends at
+    let mut end = None;
+
+    loop {
+        let next = handles.len() as u8 + 1;
+
+        tokio::select! {
+            _ = stagger.tick(), if next <= max_hops && end.is_none() => {
+                let icmp = icmp.clone();
+                let probe = Arc::new(Target {
+                    ttl: NonZeroU8::new(next),
+                    ..target.clone()
+                });
+
+                handles.push(probes.spawn(
+                    async move { (next, icmp.echo(&probe, addr).await) }.in_current_span(),
+                ));
+            }
+            Some(probe) = probes.join_next() => {
+                let (ttl, res) = match probe {
+                    Ok(probe) => probe,
+                    Err(err) if err.is_cancelled() => continue,
+                    Err(err) => return Err(Failure::new("internal", err)),
+                };
+
+                match res {
+                    Err(err) if err.kind != "timeout" => return Err(err),
+                    res => {
+                        if ends_path(&res) && end.is_none_or(|end| ttl < end) {
+                            end = Some(ttl);
+
+                            // hops past the end would end there as well
+                            for handle in &handles[usize::from(ttl)..] {
+                                handle.abort();
+                            }
+                        }
+
+                        responses[usize::from(ttl - 1)] = res;
+                    }
+                }
+            }
+            else => break,
+        }
+    }
+
+    if let Some(end) = end {
+        responses.truncate(usize::from(end));
+    }
+
+    Ok(responses)
+}
+
+/// Whether the response comes from the end of the path: the echo reply or
+/// an error other than the TTL running out, like the destination being
+/// unreachable.
+fn ends_path(res: &Result<Response, Failure>) -> bool {
+    res.as_ref().is_ok_and(|response| {
+        response
+            .error
+            .as_ref()
+            .is_none_or(|failure| failure.kind != "ttl")
+    })
+}
+
+/// Returns the number of hops to the destination, the first TTL whose
+/// response ends the path. Larger TTLs would end there as well.
+pub fn path_length(responses: &[Result<Response, Failure>]) -> Option<u8> {
+    responses
+        .iter()
+        .position(ends_path)
+        .map(|idx| idx as u8 + 1)
+}
+
+/// Traces the route to the address like `traceroute`, with several probes
+/// per hop. A path that breaks ends with the first hop that never answered.
+pub async fn snapshot(icmp: &Engine, target: &Target, addr: IpAddr) -> Result<Vec<Hop>, Failure> {
+    let mut hops = vec![Hop::default(); usize::from(MAX_HOPS)];
+    let mut max_hops = MAX_HOPS;
+
+    for _ in 0..ROUNDS {
+        let responses = round(icmp, target, addr, max_hops).await?;
+
+        if let Some(length) = path_length(&responses) {
+            max_hops = length;
+            hops.truncate(usize::from(length));
+        }
+
+        for (hop, res) in hops.iter_mut().zip(&responses) {
+            hop.add(res);
+        }
+    }
+
+    trim(&mut hops);
+
+    Ok(hops)
+}
+
+/// Drops the hops after the first one that never answered following the
+/// last that did, as the path breaks there.
+fn trim(hops: &mut Vec<Hop>) {
+    let answered = hops
+        .iter()
+        .rposition(|hop| hop.summary.received > 0)
+        .map_or(0, |idx| idx + 1);
+
+    hops.truncate(answered + 1);
+}
+
+/// Per-hop statistics of a route probed continuously, like `mtr` does.
+#[derive(Debug, Default)]
+pub struct Route {
+    hops: Vec<Hop>,
+    /// Hops to the destination, `None` until it answered.
+    length: Option<u8>,
+}
+
+/// A difference between a round and the route seen before.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Change {
+    /// The destination answered at another distance.
+    Length { from: Option<u8>, to: u8 },
+    /// A router that wasn't seen at this hop before answered.
+    Responder { hop: u8, addr: IpAddr },
+}
+
+impl Route {
+    /// TTLs the next round has to probe.
+    pub fn max_hops(&self) -> u8 {
+        self.length.unwrap_or(MAX_HOPS)
+    }
+
+    /// Adds the responses of a round and returns how the route changed.
+    /// Hops that start answering aren't a change, neither is a round that
+    /// didn't reach the destination.
+    pub fn add(&mut self, responses: &[Result<Response, Failure>]) -> Vec<Change> {
+        let mut changes = Vec::new();
+
+        if let Some(length) = path_length(responses)
+            && self.length != Some(length)
+        {
+            changes.push(Change::Length {
+                from: self.length,
+                to: length,
+            });
+            self.length = Some(length);
+            self.hops.truncate(usize::from(length));
+        }
+
+        let responses = &responses[..responses.len().min(usize::from(self.max_hops()))];
+
+        if self.hops.len() < responses.len() {
+            self.hops.resize_with(responses.len(), Hop::default);
+        }
+
+        for (idx, (hop, res)) in self.hops.iter_mut().zip(responses).enumerate() {
+            if let Ok(response) = res
+                && !hop.responders.is_empty()
+                && !hop.responders.contains(&response.from)
+            {
+                changes.push(Change::Responder {
+                    hop: idx as u8 + 1,
+                    addr: response.from,
+                });
+            }
+
+            hop.add(res);
+        }
+
+        changes
+    }
+
+    /// Returns the hops with their statistics since the last call and starts
+    /// over, the routers seen so far are kept.
+    pub fn take(&mut self) -> Vec<Hop> {
+        let mut hops = self.hops.clone();
+        trim(&mut hops);
+
+        for hop in &mut self.hops {
+            hop.summary = Summary::default();
+        }
+
+        hops
+    }
+}
+
+/// Returns the index of the hop where loss starts that continues up to the
+/// last hop. Loss at single routers only, which often limit the rate of
+/// their ICMP errors, doesn't count.
+pub fn loss_start(hops: &[Hop]) -> Option<usize> {
+    let lossy = |hop: &Hop| hop.summary.received < hop.summary.sent;
+
+    if !hops.last().is_some_and(lossy) {
+        return None;
+    }
+
+    Some(
+        hops.iter()
+            .rposition(|hop| !lossy(hop))
+            .map_or(0, |idx| idx + 1),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    const ROUTER: IpAddr = IpAddr::V4(Ipv4Addr::new(198, 51, 100, 1));
+    const DESTINATION: IpAddr = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+
+    fn response(from: IpAddr, kind: Option<&'static str>) -> Result<Response, Failure> {
+        Ok(Response {
+            from,
+            rtt: Duration::from_millis(10),
+            seq: 0,
+            error: kind.map(|kind| Failure::new(kind, kind)),
+        })
+    }
+
+    fn lost() -> Result<Response, Failure> {
+        Err(Failure::new("timeout", "no reply received"))
+    }
+
+    #[test]
+    fn path_ends_at_the_destination_or_an_error() {
+        let responses = [
+            response(ROUTER, Some("ttl")),
+            lost(),
+            response(DESTINATION, None),
+            response(DESTINATION, None),
+        ];
+        assert_eq!(path_length(&responses), Some(3));
+
+        let responses = [response(ROUTER, Some("unreachable")), lost()];
+        assert_eq!(path_length(&responses), Some(1));
+
+        assert_eq!(path_length(&[response(ROUTER, Some("ttl")), lost()]), None);
+    }
+
+    #[test]
+    fn route_changes_are_reported() {
+        const OTHER: IpAddr = IpAddr::V4(Ipv4Addr::new(198, 51, 100, 2));
+
+        let mut route = Route::default();
+        assert_eq!(route.max_hops(), MAX_HOPS);
+
+        let changes = route.add(&[lost(), response(DESTINATION, None), lost()]);
+        assert_eq!(changes, [Change::Length { from: None, to: 2 }]);
+        assert_eq!(route.max_hops(), 2);
+
+        // a hop that starts answering isn't a change, a different router is
+        assert!(
+            route
+                .add(&[response(ROUTER, Some("ttl")), lost()])
+                .is_empty()
+        );
+        assert_eq!(
+            route.add(&[response(OTHER, Some("ttl")), response(DESTINATION, None)]),
+            [Change::Responder {
+                hop: 1,
+                addr: OTHER
+            }]
+        );
+
+        let hops = route.take();
+        assert_eq!(hops[0].responders, [ROUTER, OTHER]);
+        assert_eq!((hops[1].summary.sent, hops[1].summary.received), (3, 2));
+        assert!(route.take().iter().all(|hop| hop.summary.sent == 0));
+
+        // without the destination answering, the report ends at the break
+        let mut route = Route::default();
+        let mut responses = vec![response(ROUTER, Some("ttl"))];
+        responses.resize_with(usize::from(MAX_HOPS), lost);
+        assert!(route.add(&responses).is_empty());
+        assert_eq!(route.take().len(), 2);
+    }
+
+    #[test]
+    fn loss_starts_where_it_continues_to_the_end() {
+        let hop = |received: bool| {
+            let mut hop = Hop::default();
+            hop.add(&response(ROUTER, None));
+            if !received {
+                hop.add(&lost());
+            }
+            hop
+        };
+
+        assert_eq!(loss_start(&[hop(true), hop(false), hop(true)]), None);
+        assert_eq!(
+            loss_start(&[hop(false), hop(true), hop(false), hop(false)]),
+            Some(2)
+        );
+        assert_eq!(loss_start(&[hop(false), hop(false)]), Some(0));
+        assert_eq!(loss_start(&[]), None);
+    }
+
+    #[test]
+    fn hops_list_their_responders() {
+        let mut hop = Hop::default();
+        hop.add(&response(ROUTER, Some("ttl")));
+        hop.add(&lost());
+        hop.add(&response(ROUTER, Some("ttl")));
+
+        assert_eq!(hop.responders, [ROUTER]);
+        assert!(
+            hop.to_string()
+                .starts_with("198.51.100.1: 2/3 received, 33.3% loss"),
+            "{hop}"
+        );
+
+        let mut hop = Hop::default();
+        hop.add(&lost());
+        assert_eq!(hop.to_string(), "*: 0/1 received, 100.0% loss");
+    }
+}
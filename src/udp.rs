@@ -0,0 +1,84 @@
+use std::{io, net::SocketAddr, time::Instant};
+
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::{net::UdpSocket, time::timeout};
+
+use crate::{
+    bind,
+    config::{Binding, Target},
+    probe::{Failure, Reply},
+    tcp,
+};
+
+/// Payload sent when none is configured.
+const DEFAULT_PAYLOAD: &str = "pinger";
+
+/// Largest datagram that is read, longer replies are truncated.
+const MAX_REPLY_SIZE: usize = 65536;
+
+/// Sends the payload in a single datagram and waits for the reply. Without an
+/// expected reply the server has to echo the payload back as in RFC 862.
+pub async fn probe(target: &Target, addr: SocketAddr) -> Result<Reply, Failure> {
+    let payload = target.payload.as_deref().unwrap_or(DEFAULT_PAYLOAD);
+    let start = Instant::now();
+
+    let reply = timeout(
+        target.timeout,
+        exchange(addr, &target.binding, payload.as_bytes()),
+    )
+    .await
+    .map_err(|_| Failure::new("timeout", "no reply received"))?
+    .map_err(|err| Failure::new(tcp::error_kind(&err), err))?;
+
+    let rtt = start.elapsed();
+
+    match &target.expect_reply {
+        Some(expected) => {
+            if !reply
+                .windows(expected.len())
+                .any(|window| window == expected.as_bytes())
+            {
+                return Err(Failure::new(
+                    "reply",
+                    format!("reply doesn't contain {expected:?}"),
+                ));
+            }
+        }
+        None => {
+            if reply != payload.as_bytes() {
+                return Err(Failure::new("reply", "reply doesn't match the payload"));
+            }
+        }
+    }
+
+    Ok(Reply {
+        rtt,
+        detail: Some(format!("{} bytes", reply.len())),
+        warning: None,
+        phases: Vec::new(),
+    })
+}
+
+async fn exchange(addr: SocketAddr, binding: &Binding, payload: &[u8]) -> io::Result<Vec<u8>> {
+    let socket = connect(addr, binding).await?;
+    socket.send(payload).await?;
+
+    let mut buf = vec![0; MAX_REPLY_SIZE];
+    let len = socket.recv(&mut buf).await?;
+    buf.truncate(len);
+
+    Ok(buf)
+}
+
+/// Opens a UDP socket on an ephemeral port that only exchanges datagrams with
+/// `addr`. Port unreachable errors surface as refused connections on receive.
+pub async fn connect(addr: SocketAddr, binding: &Binding) -> io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::for_address(addr), Type::DGRAM, Some(Protocol::UDP))?;
+    bind::apply(&socket, binding)?;
+    socket.set_nonblocking(true)?;
+
+    let socket = UdpSocket::from_std(socket.into())?;
+    socket.connect(addr).await?;
+
+    Ok(socket)
+}